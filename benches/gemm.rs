@@ -0,0 +1,115 @@
+// cargo bench --bench gemm
+//
+// Times the blocked matrix multiply against the plain strided triple loop it replaced, on the
+// shapes a dense layer sees while training on MNIST.
+use neural_network::layer::convolution::ConvolutionLayer;
+use neural_network::layer::dense::DenseLayer;
+use neural_network::tensor::{Tensor, TensorView};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// the average time of `f` over at least half a second
+fn time(mut f: impl FnMut()) -> Duration {
+    f();
+    let start = Instant::now();
+    let mut runs = 0;
+    while start.elapsed() < Duration::from_millis(500) {
+        f();
+        runs += 1;
+    }
+    start.elapsed() / runs
+}
+
+// what `TensorView::matmul` used to be
+fn naive(a: &TensorView, b: &TensorView) -> Tensor {
+    let (m, k, n) = (a.shape()[0], a.shape()[1], b.shape()[1]);
+    let mut out = Tensor::zeros(&[m, n]);
+    for i in 0..m {
+        for p in 0..k {
+            let x = a.get(&[i, p]);
+            for (j, o) in out.row_mut(i).iter_mut().enumerate() {
+                *o += x * b.get(&[p, j]);
+            }
+        }
+    }
+    out
+}
+
+fn report(name: &str, baseline: Duration, blocked: Duration) {
+    println!(
+        "{:<40} naive {:>10.3?}  blocked {:>10.3?}  {:>5.1}x",
+        name,
+        baseline,
+        blocked,
+        baseline.as_secs_f64() / blocked.as_secs_f64()
+    );
+}
+
+fn main() {
+    let input = Tensor::from_fn(&[32, 784], |i| (i as f32 * 0.01).sin());
+    let weights = Tensor::from_fn(&[300, 784], |i| (i as f32 * 0.02).cos());
+    let grad = Tensor::from_fn(&[32, 300], |i| (i as f32 * 0.03).sin());
+
+    // forward: x [32, 784] . w^T [784, 300]
+    report(
+        "x . w^T      [32, 784] x [784, 300]",
+        time(|| {
+            black_box(naive(&input.view(), &weights.t()));
+        }),
+        time(|| {
+            black_box(input.view().matmul(&weights.t()));
+        }),
+    );
+    // weight gradient: g^T [300, 32] . x [32, 784]
+    report(
+        "g^T . x      [300, 32] x [32, 784]",
+        time(|| {
+            black_box(naive(&grad.t(), &input.view()));
+        }),
+        time(|| {
+            black_box(grad.t().matmul(&input.view()));
+        }),
+    );
+    // input gradient: g [32, 300] . w [300, 784]
+    report(
+        "g . w        [32, 300] x [300, 784]",
+        time(|| {
+            black_box(naive(&grad.view(), &weights.view()));
+        }),
+        time(|| {
+            black_box(grad.matmul(&weights));
+        }),
+    );
+    let square = Tensor::from_fn(&[512, 512], |i| (i as f32 * 0.001).sin());
+    report(
+        "square       [512, 512] x [512, 512]",
+        time(|| {
+            black_box(naive(&square.view(), &square.view()));
+        }),
+        time(|| {
+            black_box(square.matmul(&square));
+        }),
+    );
+
+    let mut layer = DenseLayer::new(784, 300);
+    let step = time(|| {
+        layer.f_prop(black_box(&input));
+        black_box(layer.b_prop(&grad));
+    });
+    println!(
+        "{:<40} {:>10.3?}",
+        "DenseLayer f_prop + b_prop, batch 32", step
+    );
+
+    let images = Tensor::from_fn(&[32, 1, 28, 28], |i| (i as f32 * 0.01).sin());
+    let mut conv = ConvolutionLayer::new((1, 28, 28), (8, 5)).unwrap();
+    let conv_grad = Tensor::from_fn(&[32, 8 * 24 * 24], |i| (i as f32 * 0.03).sin());
+    let step = time(|| {
+        conv.f_prop(black_box(&images));
+        black_box(conv.b_prop(&conv_grad));
+    });
+    println!(
+        "{:<40} {:>10.3?}",
+        "Conv 1->8 5x5 f_prop + b_prop, batch 32", step
+    );
+}
@@ -1,13 +1,16 @@
 use mnist::Mnist;
 use neural_network::activations::ActivationFn;
 use neural_network::activations::Sigmoid;
+use neural_network::activations::Softmax;
+use neural_network::data::{DataLoader, Lazy};
 use neural_network::layer::dense::DenseLayer;
 use neural_network::layer::LayerType;
-use neural_network::loss::MSE;
+use neural_network::loss::CrossEntropy;
 use neural_network::network::Net;
 use neural_network::network::Network;
+use neural_network::optim::SGD;
+use neural_network::rng;
 use neural_network::trainer::Trainer;
-use rand::thread_rng;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut mnist = Mnist::from_download()?;
@@ -22,7 +25,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let train_size = 60000;
     let test_size = 100;
 
-    let mut rng = thread_rng();
+    // `rng::seed` first makes the sample (and the initial weights) the same every run
+    let mut rng = rng::rng();
 
     let train_range = rand::seq::index::sample(&mut rng, 60000, train_size);
     let test_range = rand::seq::index::sample(&mut rng, 10000, test_size);
@@ -73,34 +77,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Net::Layer(LayerType::Dense(DenseLayer::new(100, 50))),
         Net::Activation(ActivationFn::Sigmoid(Sigmoid::default())),
         Net::Layer(LayerType::Dense(DenseLayer::new(50, 10))),
-        Net::Activation(ActivationFn::Sigmoid(Sigmoid::default())),
+        Net::Activation(ActivationFn::Softmax(Softmax::default())),
     ]);
 
-
     // let mut network = Network::from_file("./models/mnist")?;
     println!("Training started...");
 
+    // reads the samples in place, the test below uses them too
+    let dataset = Lazy::new(train_set.len(), |i| {
+        Ok((train_set[i].clone(), train_answer[i].clone()))
+    });
+    let mut loader = DataLoader::new(dataset, 32).shuffle(true).workers(2);
     Trainer::cpu(
         &mut network,
-        MSE {},
-        &train_set,
-        &train_answer,
-        0.1f32,
+        CrossEntropy,
+        &mut loader,
+        &mut SGD::new(0.1f32),
         1000,
         true,
-        "./models/mnist"
-    );
+        "./models/mnist",
+    )?;
 
     println!("Training finished...\n\n");
 
     println!("---------- Against original train set ----------");
     let mut correct = 0f32;
     for img_num in 0..test_size {
-        let out = network.predict(&train_set[img_num]);
+        let out = network.predict(&train_set[img_num])?;
         let answer = &train_answer[img_num];
 
         let pred = max_f32(&out)?;
-        let truth = max_f32(&answer)?;
+        let truth = max_f32(answer)?;
         if pred.0 == truth.0 {
             correct += 1f32;
         }
@@ -121,11 +128,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("\n\n---------- Against Test set ----------");
     let mut correct = 0f32;
     for img_num in 0..test_size {
-        let out = network.predict(&test_set[img_num]);
+        let out = network.predict(&test_set[img_num])?;
         let answer = &test_answer[img_num];
 
         let pred = max_f32(&out)?;
-        let truth = max_f32(&answer)?;
+        let truth = max_f32(answer)?;
         if pred.0 == truth.0 {
             correct += 1f32;
         }
@@ -146,7 +153,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn max_f32(v: &Vec<f32>) -> Result<(usize, &f32), Box<dyn std::error::Error>> {
+fn max_f32(v: &[f32]) -> Result<(usize, &f32), Box<dyn std::error::Error>> {
     let mut iter = v.iter().enumerate();
     let init = iter.next().ok_or("Need at least one input")?;
     let result = iter.try_fold(init, |acc, x| {
@@ -159,7 +166,7 @@ fn max_f32(v: &Vec<f32>) -> Result<(usize, &f32), Box<dyn std::error::Error>> {
         Some(max)
     });
 
-    if !result.is_some() {
+    if result.is_none() {
         return Err("NaN value exists".into());
     }
     Ok(result.unwrap())
@@ -1,81 +1,153 @@
-use super::layer::LayerOutput;
+use super::layer::{accumulate, LayerOutput, Param};
+use crate::error::{Error, Result};
+use crate::tensor::Tensor;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize,  Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum ActivationFn {
     Tanh(Tanh),
     Sigmoid(Sigmoid),
     Relu(Relu),
+    Softmax(Softmax),
+    LogSoftmax(LogSoftmax),
+    LeakyRelu(LeakyRelu),
+    Elu(Elu),
+    Selu(Selu),
+    Gelu(Gelu),
+    Silu(Silu),
+    Softplus(Softplus),
+    Mish(Mish),
+    HardSigmoid(HardSigmoid),
+    HardTanh(HardTanh),
+    Identity(Identity),
+    PRelu(PRelu),
+    Swish(Swish),
+}
+
+impl ActivationFn {
+    pub fn as_activation(&self) -> &dyn Activation {
+        match self {
+            ActivationFn::Tanh(tanh) => tanh,
+            ActivationFn::Sigmoid(sigmoid) => sigmoid,
+            ActivationFn::Relu(relu) => relu,
+            ActivationFn::Softmax(softmax) => softmax,
+            ActivationFn::LogSoftmax(log_softmax) => log_softmax,
+            ActivationFn::LeakyRelu(leaky_relu) => leaky_relu,
+            ActivationFn::Elu(elu) => elu,
+            ActivationFn::Selu(selu) => selu,
+            ActivationFn::Gelu(gelu) => gelu,
+            ActivationFn::Silu(silu) => silu,
+            ActivationFn::Softplus(softplus) => softplus,
+            ActivationFn::Mish(mish) => mish,
+            ActivationFn::HardSigmoid(hard_sigmoid) => hard_sigmoid,
+            ActivationFn::HardTanh(hard_tanh) => hard_tanh,
+            ActivationFn::Identity(identity) => identity,
+            ActivationFn::PRelu(prelu) => prelu,
+            ActivationFn::Swish(swish) => swish,
+        }
+    }
+
+    pub fn as_activation_mut(&mut self) -> &mut dyn Activation {
+        match self {
+            ActivationFn::Tanh(tanh) => tanh,
+            ActivationFn::Sigmoid(sigmoid) => sigmoid,
+            ActivationFn::Relu(relu) => relu,
+            ActivationFn::Softmax(softmax) => softmax,
+            ActivationFn::LogSoftmax(log_softmax) => log_softmax,
+            ActivationFn::LeakyRelu(leaky_relu) => leaky_relu,
+            ActivationFn::Elu(elu) => elu,
+            ActivationFn::Selu(selu) => selu,
+            ActivationFn::Gelu(gelu) => gelu,
+            ActivationFn::Silu(silu) => silu,
+            ActivationFn::Softplus(softplus) => softplus,
+            ActivationFn::Mish(mish) => mish,
+            ActivationFn::HardSigmoid(hard_sigmoid) => hard_sigmoid,
+            ActivationFn::HardTanh(hard_tanh) => hard_tanh,
+            ActivationFn::Identity(identity) => identity,
+            ActivationFn::PRelu(prelu) => prelu,
+            ActivationFn::Swish(swish) => swish,
+        }
+    }
+
+    pub fn params(&mut self) -> Vec<Param<'_>> {
+        self.as_activation_mut().params()
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.as_activation_mut().zero_grad()
+    }
 }
 
 pub trait Activation {
+    fn f_prop(&mut self, layer_out: &LayerOutput) -> LayerOutput;
+    fn f_prop_ref(&self, layer_out: &LayerOutput) -> LayerOutput;
+    // One sample for inference, from and into plain slices of the same length. `channels` is the
+    // size of dimension 1 of the batched input (the depth of conv outputs).
+    fn infer(&self, input: &[f32], output: &mut [f32], channels: usize);
+    // Like the layers, gradients of trainable parameters are accumulated here until `zero_grad`.
+    fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor;
+
+    // Trainable parameters, if the activation has any. They are updated by the optimizer along
+    // with the layer weights.
+    fn params(&mut self) -> Vec<Param<'_>> {
+        Vec::new()
+    }
+
+    fn zero_grad(&mut self) {}
+}
+
+// An activation applied to every element on its own. Implementing this is enough to get the
+// `Activation` impl.
+pub trait ElementWise {
     fn activation(&self, x: f32) -> f32;
     fn derivative(&self, x: f32) -> f32;
     fn set_input(&mut self, input: LayerOutput);
     fn get_input(&self) -> &LayerOutput;
+}
 
+impl<T: ElementWise> Activation for T {
     fn f_prop(&mut self, layer_out: &LayerOutput) -> LayerOutput {
-        match layer_out {
-            LayerOutput::Conv(input) => {
-                self.set_input(LayerOutput::Conv(input.clone()));
-                LayerOutput::Conv(
-                    input
-                        .iter()
-                        .map(|row| row.iter().map(|i| self.activation(*i)).collect())
-                        .collect(),
-                )
-            }
-            LayerOutput::Dense(input) => {
-                self.set_input(LayerOutput::Dense(input.clone()));
-                LayerOutput::Dense(input.iter().map(|i| self.activation(*i)).collect())
-            }
-            _ => unreachable!(),
-        }
+        self.set_input(layer_out.clone());
+        self.f_prop_ref(layer_out)
     }
 
     fn f_prop_ref(&self, layer_out: &LayerOutput) -> LayerOutput {
         match layer_out {
-            LayerOutput::Conv(input) => {
-                LayerOutput::Conv(
-                    input
-                        .iter()
-                        .map(|row| row.iter().map(|i| self.activation(*i)).collect())
-                        .collect(),
-                )
-            }
-            LayerOutput::Dense(input) => {
-                LayerOutput::Dense(input.iter().map(|i| self.activation(*i)).collect())
-            }
-            _ => unreachable!(),
+            LayerOutput::Conv(input) => LayerOutput::Conv(input.map(|i| self.activation(i))),
+            LayerOutput::Dense(input) => LayerOutput::Dense(input.map(|i| self.activation(i))),
         }
     }
 
-    fn b_prop(&self, output_gradient: &[f32]) -> Vec<f32> {
-        match self.get_input() {
-            LayerOutput::Conv(input_2d) => input_2d
-                .clone()
-                .into_iter()
-                .flatten()
-                .zip(output_gradient.iter())
-                .map(|(i, og)| self.derivative(i) * og)
-                .collect(),
+    fn infer(&self, input: &[f32], output: &mut [f32], _: usize) {
+        for (o, x) in output.iter_mut().zip(input) {
+            *o = self.activation(*x);
+        }
+    }
 
-            LayerOutput::Dense(input) => input
+    // the gradient coming from the layer above may be flattened, so it only has to match the
+    // stored input in length
+    fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        let input = self.get_input().tensor();
+        assert_eq!(input.len(), output_gradient.len());
+        Tensor::new(
+            input
+                .as_slice()
                 .iter()
-                .zip(output_gradient.iter())
+                .zip(output_gradient.as_slice())
                 .map(|(i, og)| self.derivative(*i) * og)
                 .collect(),
-            _ => unreachable!(),
-        }
+            input.shape(),
+        )
     }
 }
 
 #[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Tanh {
+    #[serde(skip)]
     input: LayerOutput,
 }
 
-impl Activation for Tanh {
+impl ElementWise for Tanh {
     fn activation(&self, x: f32) -> f32 {
         x.tanh()
     }
@@ -95,10 +167,11 @@ impl Activation for Tanh {
 
 #[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Sigmoid {
+    #[serde(skip)]
     input: LayerOutput,
 }
 
-impl Activation for Sigmoid {
+impl ElementWise for Sigmoid {
     fn activation(&self, x: f32) -> f32 {
         1f32 / (1f32 + f32::exp(-x))
     }
@@ -118,10 +191,11 @@ impl Activation for Sigmoid {
 
 #[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Relu {
+    #[serde(skip)]
     input: LayerOutput,
 }
 
-impl Activation for Relu {
+impl ElementWise for Relu {
     fn activation(&self, x: f32) -> f32 {
         f32::max(0f32, x)
     }
@@ -130,7 +204,381 @@ impl Activation for Relu {
         if x > 0f32 {
             return 1f32;
         }
-        return 0f32;
+        0f32
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// max(x, 0) + slope * min(x, 0)
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct LeakyRelu {
+    pub slope: f32,
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl LeakyRelu {
+    pub fn new(slope: f32) -> Self {
+        Self {
+            slope,
+            input: LayerOutput::default(),
+        }
+    }
+}
+
+impl Default for LeakyRelu {
+    fn default() -> Self {
+        Self::new(0.01)
+    }
+}
+
+impl ElementWise for LeakyRelu {
+    fn activation(&self, x: f32) -> f32 {
+        if x > 0f32 {
+            return x;
+        }
+        self.slope * x
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        if x > 0f32 {
+            return 1f32;
+        }
+        self.slope
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// x for x > 0, alpha * (e^x - 1) otherwise
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Elu {
+    pub alpha: f32,
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl Elu {
+    pub fn new(alpha: f32) -> Self {
+        Self {
+            alpha,
+            input: LayerOutput::default(),
+        }
+    }
+}
+
+impl Default for Elu {
+    fn default() -> Self {
+        Self::new(1f32)
+    }
+}
+
+impl ElementWise for Elu {
+    fn activation(&self, x: f32) -> f32 {
+        if x > 0f32 {
+            return x;
+        }
+        self.alpha * x.exp_m1()
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        if x > 0f32 {
+            return 1f32;
+        }
+        self.alpha * x.exp()
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// ELU scaled so activations keep zero mean and unit variance ("Self-Normalizing Neural Networks")
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Selu {
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl Selu {
+    const ALPHA: f32 = 1.673_263_2;
+    const SCALE: f32 = 1.050_701;
+}
+
+impl ElementWise for Selu {
+    fn activation(&self, x: f32) -> f32 {
+        if x > 0f32 {
+            return Self::SCALE * x;
+        }
+        Self::SCALE * Self::ALPHA * x.exp_m1()
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        if x > 0f32 {
+            return Self::SCALE;
+        }
+        Self::SCALE * Self::ALPHA * x.exp()
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// x * P(X <= x) for a standard normal X. `approximate` swaps the exact erf for the cheaper tanh
+// approximation.
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Gelu {
+    pub approximate: bool,
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl Gelu {
+    // sqrt(2 / pi)
+    const TANH_SCALE: f32 = 0.797_884_6;
+    const TANH_CUBIC: f32 = 0.044_715;
+
+    pub fn new(approximate: bool) -> Self {
+        Self {
+            approximate,
+            input: LayerOutput::default(),
+        }
+    }
+
+    // standard normal cdf
+    fn cdf(x: f32) -> f32 {
+        0.5 * (1f32 + erf(x * std::f32::consts::FRAC_1_SQRT_2))
+    }
+}
+
+impl ElementWise for Gelu {
+    fn activation(&self, x: f32) -> f32 {
+        if self.approximate {
+            let inner = Self::TANH_SCALE * (x + Self::TANH_CUBIC * x.powi(3));
+            return 0.5 * x * (1f32 + inner.tanh());
+        }
+        x * Self::cdf(x)
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        if self.approximate {
+            let inner = Self::TANH_SCALE * (x + Self::TANH_CUBIC * x.powi(3));
+            let t = inner.tanh();
+            let d_inner = Self::TANH_SCALE * (1f32 + 3f32 * Self::TANH_CUBIC * x * x);
+            return 0.5 * (1f32 + t) + 0.5 * x * (1f32 - t * t) * d_inner;
+        }
+        // cdf(x) + x * pdf(x)
+        let pdf = (-0.5 * x * x).exp() / (2f32 * std::f32::consts::PI).sqrt();
+        Self::cdf(x) + x * pdf
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// Abramowitz and Stegun 7.1.26, absolute error below 1.5e-7
+fn erf(x: f32) -> f32 {
+    let t = 1f32 / (1f32 + 0.327_591_1 * x.abs());
+    let poly = t
+        * (0.254_829_6
+            + t * (-0.284_496_7 + t * (1.421_413_8 + t * (-1.453_152 + t * 1.061_405_4))));
+    (1f32 - poly * (-x * x).exp()).copysign(x)
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1f32 / (1f32 + f32::exp(-x))
+}
+
+// ln(1 + e^x), written so that large inputs don't overflow
+fn softplus(x: f32) -> f32 {
+    x.max(0f32) + (-x.abs()).exp().ln_1p()
+}
+
+// x * sigmoid(x), also known as swish
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Silu {
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl ElementWise for Silu {
+    fn activation(&self, x: f32) -> f32 {
+        x * sigmoid(x)
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        let s = sigmoid(x);
+        s * (1f32 + x * (1f32 - s))
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Softplus {
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl ElementWise for Softplus {
+    fn activation(&self, x: f32) -> f32 {
+        softplus(x)
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        sigmoid(x)
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// x * tanh(softplus(x))
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Mish {
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl ElementWise for Mish {
+    fn activation(&self, x: f32) -> f32 {
+        x * softplus(x).tanh()
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        let t = softplus(x).tanh();
+        t + x * (1f32 - t * t) * sigmoid(x)
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// clamp(x / 6 + 1 / 2, 0, 1), a piecewise linear sigmoid
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct HardSigmoid {
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl ElementWise for HardSigmoid {
+    fn activation(&self, x: f32) -> f32 {
+        (x / 6f32 + 0.5).clamp(0f32, 1f32)
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        if x > -3f32 && x < 3f32 {
+            return 1f32 / 6f32;
+        }
+        0f32
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// clamp(x, min, max)
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct HardTanh {
+    // only set through `new`, which checks them
+    min: f32,
+    max: f32,
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl HardTanh {
+    pub fn new(min: f32, max: f32) -> Result<Self> {
+        if min >= max || min.is_nan() || max.is_nan() {
+            return Err(Error::InvalidConfig(format!(
+                "HardTanh needs min < max, got {} and {}",
+                min, max
+            )));
+        }
+        Ok(Self {
+            min,
+            max,
+            input: LayerOutput::default(),
+        })
+    }
+
+    pub fn min(&self) -> f32 {
+        self.min
+    }
+
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+}
+
+impl Default for HardTanh {
+    fn default() -> Self {
+        Self {
+            min: -1f32,
+            max: 1f32,
+            input: LayerOutput::default(),
+        }
+    }
+}
+
+impl ElementWise for HardTanh {
+    fn activation(&self, x: f32) -> f32 {
+        x.clamp(self.min, self.max)
+    }
+
+    fn derivative(&self, x: f32) -> f32 {
+        if x > self.min && x < self.max {
+            return 1f32;
+        }
+        0f32
     }
 
     fn set_input(&mut self, input: LayerOutput) {
@@ -141,3 +589,461 @@ impl Activation for Relu {
         &self.input
     }
 }
+
+// Passes the layer output through unchanged, for linear output layers.
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Identity {
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl ElementWise for Identity {
+    fn activation(&self, x: f32) -> f32 {
+        x
+    }
+
+    fn derivative(&self, _: f32) -> f32 {
+        1f32
+    }
+
+    fn set_input(&mut self, input: LayerOutput) {
+        self.input = input;
+    }
+
+    fn get_input(&self) -> &LayerOutput {
+        &self.input
+    }
+}
+
+// Normalizes every sample of the batch into a probability distribution. Unlike the element wise
+// activations, every output depends on every input of the sample.
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Softmax {
+    #[serde(skip)]
+    input: LayerOutput,
+    #[serde(skip)]
+    output: Tensor,
+}
+
+impl Softmax {
+    // the inputs (logits) of the last `f_prop`
+    pub fn logits(&self) -> &Tensor {
+        self.input.tensor()
+    }
+
+    fn softmax(input: &Tensor) -> Tensor {
+        let mut out = input.clone();
+        for sample in 0..out.shape()[0] {
+            Self::softmax_inplace(out.row_mut(sample));
+        }
+        out
+    }
+
+    fn softmax_inplace(row: &mut [f32]) {
+        // shifting by the max keeps exp from overflowing
+        let max = row.iter().fold(f32::NEG_INFINITY, |m, x| m.max(*x));
+        row.iter_mut().for_each(|x| *x = (*x - max).exp());
+        let sum: f32 = row.iter().sum();
+        row.iter_mut().for_each(|x| *x /= sum);
+    }
+}
+
+impl Activation for Softmax {
+    fn f_prop(&mut self, layer_out: &LayerOutput) -> LayerOutput {
+        self.input = layer_out.clone();
+        let out = self.f_prop_ref(layer_out);
+        self.output = out.tensor().clone();
+        out
+    }
+
+    fn f_prop_ref(&self, layer_out: &LayerOutput) -> LayerOutput {
+        match layer_out {
+            LayerOutput::Conv(input) => LayerOutput::Conv(Self::softmax(input)),
+            LayerOutput::Dense(input) => LayerOutput::Dense(Self::softmax(input)),
+        }
+    }
+
+    fn infer(&self, input: &[f32], output: &mut [f32], _: usize) {
+        output.copy_from_slice(input);
+        Self::softmax_inplace(output);
+    }
+
+    fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        // Jacobian-vector product: dx.i = s.i * (g.i - sum_j(g.j * s.j))
+        assert_eq!(self.output.len(), output_gradient.len());
+        let og = output_gradient.clone().reshape(self.output.shape());
+        let mut input_grad = Tensor::zeros(self.output.shape());
+        for sample in 0..self.output.shape()[0] {
+            let s = self.output.row(sample);
+            let g = og.row(sample);
+            let gs: f32 = s.iter().zip(g).map(|(s, g)| s * g).sum();
+            for ((dx, s), g) in input_grad.row_mut(sample).iter_mut().zip(s).zip(g) {
+                *dx = s * (g - gs);
+            }
+        }
+        input_grad
+    }
+}
+
+// The log of `Softmax`, computed without going through the probabilities so it stays finite.
+// Gives the log-probabilities `NLL` expects.
+#[derive(Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct LogSoftmax {
+    #[serde(skip)]
+    output: Tensor,
+}
+
+impl LogSoftmax {
+    fn log_softmax(input: &Tensor) -> Tensor {
+        let mut out = input.clone();
+        for sample in 0..out.shape()[0] {
+            Self::log_softmax_inplace(out.row_mut(sample));
+        }
+        out
+    }
+
+    fn log_softmax_inplace(row: &mut [f32]) {
+        // x - max - ln(sum(exp(x - max)))
+        let max = row.iter().fold(f32::NEG_INFINITY, |m, x| m.max(*x));
+        let log_sum = row.iter().map(|x| (x - max).exp()).sum::<f32>().ln();
+        row.iter_mut().for_each(|x| *x -= max + log_sum);
+    }
+}
+
+impl Activation for LogSoftmax {
+    fn f_prop(&mut self, layer_out: &LayerOutput) -> LayerOutput {
+        let out = self.f_prop_ref(layer_out);
+        self.output = out.tensor().clone();
+        out
+    }
+
+    fn f_prop_ref(&self, layer_out: &LayerOutput) -> LayerOutput {
+        match layer_out {
+            LayerOutput::Conv(input) => LayerOutput::Conv(Self::log_softmax(input)),
+            LayerOutput::Dense(input) => LayerOutput::Dense(Self::log_softmax(input)),
+        }
+    }
+
+    fn infer(&self, input: &[f32], output: &mut [f32], _: usize) {
+        output.copy_from_slice(input);
+        Self::log_softmax_inplace(output);
+    }
+
+    fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        // dx.i = g.i - s.i * sum_j(g.j), with s = exp(output) the softmax
+        assert_eq!(self.output.len(), output_gradient.len());
+        let og = output_gradient.clone().reshape(self.output.shape());
+        let mut input_grad = Tensor::zeros(self.output.shape());
+        for sample in 0..self.output.shape()[0] {
+            let g = og.row(sample);
+            let sum: f32 = g.iter().sum();
+            let out = self.output.row(sample);
+            for ((dx, o), g) in input_grad.row_mut(sample).iter_mut().zip(out).zip(g) {
+                *dx = g - o.exp() * sum;
+            }
+        }
+        input_grad
+    }
+}
+
+// max(x, 0) + a * min(x, 0) with a learned slope `a`, one per channel (dimension 1 of the input,
+// i.e. the depth of conv outputs or the features of dense outputs), or a single shared one.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct PRelu {
+    pub slopes: Tensor, // [channels]
+    #[serde(skip)]
+    slope_grad: Tensor,
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl PRelu {
+    // `channels` of 1 shares a single slope across every input, 0 fails
+    pub fn new(channels: usize) -> Result<Self> {
+        Self::with_slope(channels, 0.25)
+    }
+
+    pub fn with_slope(channels: usize, slope: f32) -> Result<Self> {
+        if channels == 0 {
+            return Err(Error::InvalidConfig(
+                "PRelu needs at least one channel".into(),
+            ));
+        }
+        Ok(Self {
+            slopes: Tensor::full(&[channels], slope),
+            slope_grad: Tensor::zeros(&[channels]),
+            input: LayerOutput::default(),
+        })
+    }
+
+    // the slope index of every element of `input`
+    fn channels(&self, input: &Tensor) -> impl Iterator<Item = usize> {
+        let channels = self.slopes.len();
+        let inner: usize = input.shape().iter().skip(2).product();
+        if channels > 1 {
+            assert_eq!(
+                input.shape().get(1),
+                Some(&channels),
+                "PRelu slopes do not match the input channels"
+            );
+        }
+        (0..input.len()).map(move |i| (i / inner) % channels)
+    }
+
+    fn prelu(&self, input: &Tensor) -> Tensor {
+        let slopes = self.slopes.as_slice();
+        let data = self
+            .channels(input)
+            .zip(input.as_slice())
+            .map(|(c, x)| if *x > 0f32 { *x } else { slopes[c] * x })
+            .collect();
+        Tensor::new(data, input.shape())
+    }
+}
+
+impl Default for PRelu {
+    fn default() -> Self {
+        Self {
+            slopes: Tensor::full(&[1], 0.25),
+            slope_grad: Tensor::zeros(&[1]),
+            input: LayerOutput::default(),
+        }
+    }
+}
+
+impl Activation for PRelu {
+    fn f_prop(&mut self, layer_out: &LayerOutput) -> LayerOutput {
+        self.input = layer_out.clone();
+        self.f_prop_ref(layer_out)
+    }
+
+    fn f_prop_ref(&self, layer_out: &LayerOutput) -> LayerOutput {
+        match layer_out {
+            LayerOutput::Conv(input) => LayerOutput::Conv(self.prelu(input)),
+            LayerOutput::Dense(input) => LayerOutput::Dense(self.prelu(input)),
+        }
+    }
+
+    fn infer(&self, input: &[f32], output: &mut [f32], channels: usize) {
+        let slopes = self.slopes.as_slice();
+        let inner = input.len() / channels;
+        for (i, (o, x)) in output.iter_mut().zip(input).enumerate() {
+            let slope = if slopes.len() > 1 {
+                slopes[i / inner]
+            } else {
+                slopes[0]
+            };
+            *o = if *x > 0f32 { *x } else { slope * x };
+        }
+    }
+
+    fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        let input = self.input.tensor();
+        assert_eq!(input.len(), output_gradient.len());
+        let slopes = self.slopes.as_slice();
+        let mut slope_grad = Tensor::zeros(self.slopes.shape());
+        let mut input_grad = Tensor::zeros(input.shape());
+        for (((c, x), og), dx) in self
+            .channels(input)
+            .zip(input.as_slice())
+            .zip(output_gradient.as_slice())
+            .zip(input_grad.as_mut_slice())
+        {
+            if *x > 0f32 {
+                *dx = *og;
+            } else {
+                *dx = slopes[c] * og;
+                slope_grad.as_mut_slice()[c] += x * og;
+            }
+        }
+        accumulate(&mut self.slope_grad, &slope_grad);
+        input_grad
+    }
+
+    fn params(&mut self) -> Vec<Param<'_>> {
+        vec![Param::new(&mut self.slopes, &mut self.slope_grad)]
+    }
+
+    fn zero_grad(&mut self) {
+        self.slope_grad = Tensor::zeros(self.slopes.shape());
+    }
+}
+
+// x * sigmoid(beta * x) with a learned `beta`. A fixed beta of 1 is `Silu`.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct Swish {
+    pub beta: Tensor, // [1]
+    #[serde(skip)]
+    beta_grad: Tensor,
+    #[serde(skip)]
+    input: LayerOutput,
+}
+
+impl Swish {
+    pub fn new(beta: f32) -> Self {
+        Self {
+            beta: Tensor::full(&[1], beta),
+            beta_grad: Tensor::zeros(&[1]),
+            input: LayerOutput::default(),
+        }
+    }
+
+    fn swish(&self, input: &Tensor) -> Tensor {
+        let beta = self.beta.as_slice()[0];
+        input.map(|x| x * sigmoid(beta * x))
+    }
+}
+
+impl Default for Swish {
+    fn default() -> Self {
+        Self::new(1f32)
+    }
+}
+
+impl Activation for Swish {
+    fn f_prop(&mut self, layer_out: &LayerOutput) -> LayerOutput {
+        self.input = layer_out.clone();
+        self.f_prop_ref(layer_out)
+    }
+
+    fn f_prop_ref(&self, layer_out: &LayerOutput) -> LayerOutput {
+        match layer_out {
+            LayerOutput::Conv(input) => LayerOutput::Conv(self.swish(input)),
+            LayerOutput::Dense(input) => LayerOutput::Dense(self.swish(input)),
+        }
+    }
+
+    fn infer(&self, input: &[f32], output: &mut [f32], _: usize) {
+        let beta = self.beta.as_slice()[0];
+        for (o, x) in output.iter_mut().zip(input) {
+            *o = x * sigmoid(beta * x);
+        }
+    }
+
+    fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        let input = self.input.tensor();
+        assert_eq!(input.len(), output_gradient.len());
+        let beta = self.beta.as_slice()[0];
+        let mut beta_grad = 0f32;
+        let data = input
+            .as_slice()
+            .iter()
+            .zip(output_gradient.as_slice())
+            .map(|(x, og)| {
+                let s = sigmoid(beta * x);
+                // d/dbeta = x^2 * s * (1 - s)
+                beta_grad += og * x * x * s * (1f32 - s);
+                // d/dx = s + beta * x * s * (1 - s)
+                og * (s + beta * x * s * (1f32 - s))
+            })
+            .collect();
+        accumulate(&mut self.beta_grad, &Tensor::full(&[1], beta_grad));
+        Tensor::new(data, input.shape())
+    }
+
+    fn params(&mut self) -> Vec<Param<'_>> {
+        vec![Param::new(&mut self.beta, &mut self.beta_grad)]
+    }
+
+    fn zero_grad(&mut self) {
+        self.beta_grad = Tensor::zeros(self.beta.shape());
+    }
+}
+
+#[test]
+fn element_wise_derivatives() {
+    let activations: Vec<Box<dyn ElementWise>> = vec![
+        Box::new(Tanh::default()),
+        Box::new(Sigmoid::default()),
+        Box::new(LeakyRelu::new(0.1)),
+        Box::new(Elu::default()),
+        Box::new(Selu::default()),
+        Box::new(Gelu::new(false)),
+        Box::new(Gelu::new(true)),
+        Box::new(Silu::default()),
+        Box::new(Softplus::default()),
+        Box::new(Mish::default()),
+        Box::new(HardSigmoid::default()),
+        Box::new(HardTanh::default()),
+        Box::new(Identity::default()),
+    ];
+    // away from the kinks of the piecewise functions
+    let xs = [-4.1f32, -2.2, -0.7, -0.3, 0.4, 0.9, 1.6, 3.7];
+    let h = 1e-3;
+    for (n, a) in activations.iter().enumerate() {
+        for x in xs {
+            let numeric = (a.activation(x + h) - a.activation(x - h)) / (2f32 * h);
+            let analytic = a.derivative(x);
+            assert!(
+                (numeric - analytic).abs() < 1e-2,
+                "activation {} at {}: {} vs {}",
+                n,
+                x,
+                analytic,
+                numeric
+            );
+        }
+    }
+    assert!((Gelu::new(false).activation(1f32) - 0.841_344_7).abs() < 1e-5);
+    assert!((Selu::default().activation(-100f32) + 1.758_099_3).abs() < 1e-5);
+    assert_eq!(Softplus::default().activation(1000f32), 1000f32);
+    assert!(matches!(
+        HardTanh::new(1f32, -1f32),
+        Err(Error::InvalidConfig(_))
+    ));
+    // nothing forward propagated yet is an empty batch, not a panic
+    let unused = Softmax::default().f_prop_ref(&LayerOutput::default());
+    assert!(unused.into_tensor().is_empty());
+
+    let slope = ActivationFn::LeakyRelu(LeakyRelu::new(0.2));
+    let bytes = serde_cbor::to_vec(&slope).unwrap();
+    match serde_cbor::from_slice(&bytes).unwrap() {
+        ActivationFn::LeakyRelu(l) => assert_eq!(l.slope, 0.2),
+        _ => panic!("wrong variant"),
+    }
+}
+
+#[test]
+fn learnable_activation_gradients() {
+    // E = <f_prop(x), g>: every gradient is checked against finite differences
+    let x = Tensor::from_fn(&[2, 3, 2, 2], |i| (i as f32 * 0.9).sin() * 2f32);
+    let g = Tensor::from_fn(&[2, 3, 2, 2], |i| (i as f32 * 0.4).cos());
+    let check = |mut a: ActivationFn| {
+        let error = |a: &ActivationFn, x: &Tensor| {
+            a.as_activation()
+                .f_prop_ref(&LayerOutput::Conv(x.clone()))
+                .tensor()
+                .dot(&g)
+        };
+        let h = 1e-2;
+        a.as_activation_mut().f_prop(&LayerOutput::Conv(x.clone()));
+        // a flattened gradient is fine as long as the length matches
+        let dx = a.as_activation_mut().b_prop(&g.clone().reshape(&[2, 12]));
+        for i in (0..x.len()).filter(|i| x.as_slice()[*i].abs() > h) {
+            let mut p = x.clone();
+            p.as_mut_slice()[i] += h;
+            let mut m = x.clone();
+            m.as_mut_slice()[i] -= h;
+            let numeric = (error(&a, &p) - error(&a, &m)) / (2f32 * h);
+            assert!((dx.as_slice()[i] - numeric).abs() < 1e-2);
+        }
+        let grads: Vec<f32> = a.params()[0].grad.as_slice().to_vec();
+        for (i, analytic) in grads.into_iter().enumerate() {
+            let mut p = a.clone();
+            p.params()[0].value.as_mut_slice()[i] += h;
+            let mut m = a.clone();
+            m.params()[0].value.as_mut_slice()[i] -= h;
+            let numeric = (error(&p, &x) - error(&m, &x)) / (2f32 * h);
+            assert!(
+                (analytic - numeric).abs() < 1e-2,
+                "{} vs {}",
+                analytic,
+                numeric
+            );
+        }
+    };
+    check(ActivationFn::PRelu(PRelu::with_slope(3, 0.1).unwrap()));
+    check(ActivationFn::PRelu(PRelu::new(1).unwrap()));
+    assert!(matches!(PRelu::new(0), Err(Error::InvalidConfig(_))));
+    check(ActivationFn::Swish(Swish::new(1.5)));
+}
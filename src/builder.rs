@@ -0,0 +1,470 @@
+use crate::activations::ActivationFn;
+use crate::error::Error;
+use crate::init::Initializer;
+use crate::layer::convolution::{ConvOptions, ConvolutionLayer};
+use crate::layer::dense::DenseLayer;
+use crate::layer::pooling::{AvgPool2D, GlobalAvgPool, MaxPool2D};
+use crate::layer::LayerType;
+use crate::network::{Net, Network};
+use crate::rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::fmt;
+
+// The shape of one sample flowing between modules.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Shape {
+    Dense(usize),
+    Conv(usize, usize, usize), // (depth, height, width)
+}
+
+impl Shape {
+    // the number of values, i.e. the size once flattened
+    pub fn len(&self) -> usize {
+        match *self {
+            Shape::Dense(size) => size,
+            Shape::Conv(depth, height, width) => depth * height * width,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the size of dimension 1 of the batched tensor
+    pub(crate) fn channels(&self) -> usize {
+        match *self {
+            Shape::Dense(size) => size,
+            Shape::Conv(depth, _, _) => depth,
+        }
+    }
+}
+
+impl From<usize> for Shape {
+    fn from(size: usize) -> Self {
+        Shape::Dense(size)
+    }
+}
+
+impl From<(usize, usize, usize)> for Shape {
+    fn from((depth, height, width): (usize, usize, usize)) -> Self {
+        Shape::Conv(depth, height, width)
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shape::Dense(size) => write!(f, "dense({})", size),
+            Shape::Conv(depth, height, width) => {
+                write!(f, "conv({}, {}, {})", depth, height, width)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum BuildError {
+    // the module at `index` was made for a different input than what the previous module outputs
+    ShapeMismatch {
+        index: usize,
+        expected: Shape,
+        found: Shape,
+    },
+    // the module at `index` can't be built for (or doesn't make sense on) its input
+    InvalidModule {
+        index: usize,
+        reason: String,
+    },
+    // `Network::forward` returns [batch, size], so the last module has to output a dense shape
+    OutputNotDense(Shape),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ShapeMismatch {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "module {} expects an input of {} but gets {}",
+                index, expected, found
+            ),
+            BuildError::InvalidModule { index, reason } => {
+                write!(f, "module {}: {}", index, reason)
+            }
+            BuildError::OutputNotDense(shape) => write!(
+                f,
+                "the network has to end with a dense output, it ends with {}",
+                shape
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// Builds a `Network` from its input shape, working out the input size of every layer. The first
+// error is kept and returned by `build`, later modules are ignored.
+//
+// Dense and conv layers start with Xavier uniform weights. An activation right after one of them
+// redraws its weights with `Initializer::for_activation` (He for Relu, ...), unless `init` picked
+// the initializer. The weights come from `rng::rng()` unless `seed` or `rng` is given.
+//
+//     let network = Network::builder((1, 28, 28))
+//         .conv((8, 5))
+//         .activation(ActivationFn::Relu(Relu::default()))
+//         .dense(10)
+//         .activation(ActivationFn::Softmax(Softmax::default()))
+//         .build()?;
+pub struct NetworkBuilder {
+    shape: Shape,
+    modules: Vec<Net>,
+    error: Option<BuildError>,
+    // the last module is a layer with default weights, which the next activation may redraw
+    default_init: bool,
+    rng: ChaCha8Rng,
+}
+
+impl NetworkBuilder {
+    pub fn new(input_shape: impl Into<Shape>) -> Self {
+        Self {
+            shape: input_shape.into(),
+            modules: Vec::new(),
+            error: None,
+            default_init: false,
+            rng: rng::rng(),
+        }
+    }
+
+    // Draws the weights of the layers added from now on from `seed`, so the same builder calls
+    // make the same network.
+    pub fn seed(self, seed: u64) -> Self {
+        Self {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            ..self
+        }
+    }
+
+    // Like `seed`, with the seed drawn from `rng`.
+    pub fn rng<R: Rng + ?Sized>(self, rng: &mut R) -> Self {
+        self.seed(rng.gen())
+    }
+
+    // the output shape of the modules added so far
+    pub fn output_shape(&self) -> Shape {
+        self.shape
+    }
+
+    // A dense layer from everything the previous module outputs (conv outputs are flattened).
+    pub fn dense(self, output_size: usize) -> Self {
+        self.push_with(|input, _, rng| {
+            if output_size == 0 {
+                return Err("a dense layer needs at least one output".into());
+            }
+            let layer = DenseLayer::with_rng(input.len(), output_size, Initializer::default(), rng)
+                .map_err(|e| e.to_string())?;
+            Ok(Net::Layer(LayerType::Dense(layer)))
+        })
+        .with_default_init()
+    }
+
+    // kernel_shape: (depth, size)
+    pub fn conv(self, kernel_shape: (usize, usize)) -> Self {
+        self.conv_with(kernel_shape, ConvOptions::default())
+    }
+
+    pub fn conv_with(self, kernel_shape: (usize, usize), options: ConvOptions) -> Self {
+        self.push_with(|_, input, rng| {
+            let layer = ConvolutionLayer::try_with_options(
+                input?,
+                kernel_shape,
+                options,
+                Initializer::default(),
+                rng,
+            )?;
+            Ok(Net::Layer(LayerType::Conv(layer)))
+        })
+        .with_default_init()
+    }
+
+    pub fn max_pool(self, size: usize, stride: usize, padding: usize) -> Self {
+        self.push_with(|_, input, _| {
+            let layer = MaxPool2D::try_new(input?, size, stride, padding)?;
+            Ok(Net::Layer(LayerType::MaxPool2D(layer)))
+        })
+    }
+
+    pub fn avg_pool(self, size: usize, stride: usize, padding: usize) -> Self {
+        self.push_with(|_, input, _| {
+            let layer = AvgPool2D::try_new(input?, size, stride, padding)?;
+            Ok(Net::Layer(LayerType::AvgPool2D(layer)))
+        })
+    }
+
+    pub fn global_avg_pool(self) -> Self {
+        self.push_with(|_, input, _| {
+            let layer = GlobalAvgPool::try_new(input?)?;
+            Ok(Net::Layer(LayerType::GlobalAvgPool(layer)))
+        })
+    }
+
+    pub fn activation(self, activation: ActivationFn) -> Self {
+        if self.default_init {
+            return self
+                .init(Initializer::for_activation(&activation))
+                .module(Net::Activation(activation));
+        }
+        self.module(Net::Activation(activation))
+    }
+
+    // Redraws the weights of the layer just added with `init`, which the following activation
+    // then keeps.
+    pub fn init(mut self, init: Initializer) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        let index = self.modules.len().saturating_sub(1);
+        let initialized = match self.modules.last_mut() {
+            Some(Net::Layer(layer)) => layer.initialize(init, &mut self.rng),
+            _ => Ok(false),
+        };
+        let reason = match initialized {
+            Ok(true) => None,
+            Ok(false) => Some("init has to follow a dense or conv layer".into()),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(reason) = reason {
+            self.error = Some(BuildError::InvalidModule { index, reason });
+        }
+        self.default_init = false;
+        self
+    }
+
+    // An already built layer or activation. It is checked against the current shape instead of
+    // being sized from it.
+    pub fn module(mut self, module: Net) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        self.default_init = false;
+        let index = self.modules.len();
+        match output_shape(&module, self.shape, index) {
+            Ok(shape) => {
+                self.shape = shape;
+                self.modules.push(module);
+            }
+            Err(e) => self.error = Some(e),
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<Network, Error> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        if let Shape::Conv(..) = self.shape {
+            return Err(BuildError::OutputNotDense(self.shape).into());
+        }
+        Ok(Network::new(self.modules))
+    }
+
+    fn with_default_init(mut self) -> Self {
+        self.default_init = self.error.is_none();
+        self
+    }
+
+    // `make` gets the current shape, the same shape as a (depth, height, width) if it is one and
+    // the generator to draw weights from
+    fn push_with(
+        mut self,
+        make: impl FnOnce(
+            Shape,
+            Result<(usize, usize, usize), String>,
+            &mut ChaCha8Rng,
+        ) -> Result<Net, String>,
+    ) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        let conv = match self.shape {
+            Shape::Conv(depth, height, width) => Ok((depth, height, width)),
+            shape => Err(format!(
+                "needs a (depth, height, width) input, got {}",
+                shape
+            )),
+        };
+        match make(self.shape, conv, &mut self.rng) {
+            Ok(module) => self.module(module),
+            Err(reason) => {
+                let index = self.modules.len();
+                Self {
+                    error: Some(BuildError::InvalidModule { index, reason }),
+                    ..self
+                }
+            }
+        }
+    }
+}
+
+// What `module` outputs for an input of `input`, or why it can't take it.
+pub(crate) fn output_shape(module: &Net, input: Shape, index: usize) -> Result<Shape, BuildError> {
+    let mismatch = |expected: Shape| {
+        // like `NetworkBuilder::conv`, a dense output is never taken for a (depth, height, width)
+        if expected == input {
+            return Ok(());
+        }
+        Err(BuildError::ShapeMismatch {
+            index,
+            expected,
+            found: input,
+        })
+    };
+    match module {
+        Net::Layer(LayerType::Dense(layer)) => {
+            let (output_size, input_size) = (layer.weights.shape()[0], layer.weights.shape()[1]);
+            if input_size != input.len() {
+                return Err(BuildError::ShapeMismatch {
+                    index,
+                    expected: Shape::Dense(input_size),
+                    found: input,
+                });
+            }
+            Ok(Shape::Dense(output_size))
+        }
+        Net::Layer(LayerType::Conv(layer)) => {
+            mismatch(layer.input_shape.into())?;
+            Ok(layer.output_shape.into())
+        }
+        Net::Layer(LayerType::MaxPool2D(layer)) => {
+            mismatch(layer.input_shape().into())?;
+            Ok(layer.output_shape().into())
+        }
+        Net::Layer(LayerType::AvgPool2D(layer)) => {
+            mismatch(layer.input_shape().into())?;
+            Ok(layer.output_shape().into())
+        }
+        Net::Layer(LayerType::GlobalAvgPool(layer)) => {
+            mismatch(layer.input_shape.into())?;
+            Ok(Shape::Dense(layer.input_shape.0))
+        }
+        Net::Activation(ActivationFn::PRelu(prelu)) => {
+            let slopes = prelu.slopes.len();
+            if slopes != 1 && slopes != input.channels() {
+                return Err(BuildError::InvalidModule {
+                    index,
+                    reason: format!(
+                        "PRelu has {} slopes but its input {} has {} channels",
+                        slopes,
+                        input,
+                        input.channels()
+                    ),
+                });
+            }
+            Ok(input)
+        }
+        Net::Activation(_) => Ok(input),
+    }
+}
+
+#[test]
+fn builder_infers_and_validates_shapes() {
+    use crate::activations::{PRelu, Relu, Softmax};
+    use crate::tensor::Tensor;
+
+    let builder = NetworkBuilder::new((1, 12, 12))
+        .conv((4, 3))
+        .activation(ActivationFn::Relu(Relu::default()))
+        .max_pool(2, 2, 0);
+    assert_eq!(builder.output_shape(), Shape::Conv(4, 5, 5));
+    let network = builder
+        .dense(10)
+        .activation(ActivationFn::Softmax(Softmax::default()))
+        .build()
+        .unwrap();
+    let out = network.forward_ref(&Tensor::zeros(&[2, 144])).unwrap();
+    assert_eq!(out.shape(), &[2, 10]);
+
+    let mismatch = NetworkBuilder::new(8)
+        .dense(4)
+        .module(Net::Layer(LayerType::Dense(DenseLayer::new(5, 2))))
+        .build();
+    assert!(matches!(
+        mismatch,
+        Err(Error::Build(BuildError::ShapeMismatch {
+            index: 1,
+            expected: Shape::Dense(5),
+            found: Shape::Dense(4),
+        }))
+    ));
+
+    let too_small = NetworkBuilder::new((1, 4, 4)).conv((2, 5)).dense(1).build();
+    assert!(matches!(
+        too_small,
+        Err(Error::Build(BuildError::InvalidModule { index: 0, .. }))
+    ));
+    let conv_after_dense = NetworkBuilder::new(16).dense(16).conv((1, 3)).build();
+    assert!(matches!(
+        conv_after_dense,
+        Err(Error::Build(BuildError::InvalidModule { index: 1, .. }))
+    ));
+    // the same for a conv layer built beforehand, even with the right number of values
+    let conv = ConvolutionLayer::new((1, 4, 4), (1, 3)).unwrap();
+    let conv_after_dense = NetworkBuilder::new(16)
+        .dense(16)
+        .module(Net::Layer(LayerType::Conv(conv)))
+        .build();
+    assert!(matches!(
+        conv_after_dense,
+        Err(Error::Build(BuildError::ShapeMismatch {
+            index: 1,
+            expected: Shape::Conv(1, 4, 4),
+            found: Shape::Dense(16),
+        }))
+    ));
+    let prelu = NetworkBuilder::new((3, 4, 4))
+        .activation(ActivationFn::PRelu(PRelu::new(2).unwrap()))
+        .build();
+    assert!(matches!(
+        prelu,
+        Err(Error::Build(BuildError::InvalidModule { index: 0, .. }))
+    ));
+    // an explicit initializer is kept by the activation after it, pooling has nothing to draw
+    let zeros = NetworkBuilder::new(3)
+        .dense(2)
+        .init(Initializer::Constant(0.5))
+        .activation(ActivationFn::Relu(Relu::default()))
+        .build()
+        .unwrap();
+    assert_eq!(
+        zeros.predict_ref(&[1f32, 0f32, 1f32]).unwrap(),
+        vec![1f32; 2]
+    );
+    assert!(matches!(
+        NetworkBuilder::new((1, 4, 4))
+            .max_pool(2, 2, 0)
+            .init(Initializer::Zeros)
+            .build(),
+        Err(Error::Build(BuildError::InvalidModule { index: 0, .. }))
+    ));
+    assert!(matches!(
+        NetworkBuilder::new((1, 4, 4)).conv((1, 3)).build(),
+        Err(Error::Build(BuildError::OutputNotDense(Shape::Conv(
+            1, 2, 2
+        ))))
+    ));
+
+    // the weights only depend on the generator handed in
+    let drawn = |seed| {
+        let network = NetworkBuilder::new(3)
+            .rng(&mut ChaCha8Rng::seed_from_u64(seed))
+            .dense(2)
+            .build()
+            .unwrap();
+        network.predict_ref(&[1f32, -1f32, 0.5f32]).unwrap()
+    };
+    assert_eq!(drawn(7), drawn(7));
+    assert_ne!(drawn(7), drawn(8));
+}
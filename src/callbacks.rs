@@ -0,0 +1,431 @@
+use crate::error::{Error, Result};
+use crate::metrics::{Monitor, Report};
+use crate::network::Network;
+use crate::optim::Optimizer;
+use std::f32::consts::PI;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// What a callback gets to see, and change, at each hook.
+pub struct Context<'a> {
+    pub network: &'a Network,
+    pub optimizer: &'a mut dyn Optimizer,
+    // the current epoch: the first one to train in `on_train_begin`, past 0 after
+    // `Trainer::resume`, and the last one trained in `on_train_end`
+    pub epoch: usize,
+    // the batch within the epoch, for the batch hooks
+    pub batch: usize,
+    // batches in an epoch
+    pub batches: usize,
+    // optimizer steps taken so far, kept across `resume`
+    pub step: usize,
+    // the optimizer's rate when the run started, before any schedule, kept across `resume`
+    pub initial_learning_rate: f32,
+    pub(crate) stop: bool,
+}
+
+impl Context<'_> {
+    // Ends training once the current hook returns: after this batch in a batch hook, with the
+    // epoch cut short but still reported (and checkpointed), after this epoch in an epoch hook.
+    // `on_train_end` still runs.
+    pub fn stop(&mut self) {
+        self.stop = true;
+    }
+}
+
+// Hooks `Trainer::fit` calls around training. Every hook does nothing by default, and an error
+// from any of them stops training with that error.
+pub trait Callback {
+    fn on_train_begin(&mut self, _ctx: &mut Context) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_train_end(&mut self, _ctx: &mut Context) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_epoch_begin(&mut self, _ctx: &mut Context) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_epoch_end(&mut self, _ctx: &mut Context, _report: &Report) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_batch_begin(&mut self, _ctx: &mut Context) -> Result<()> {
+        Ok(())
+    }
+
+    // `loss` is the mean loss of the samples in the batch
+    fn on_batch_end(&mut self, _ctx: &mut Context, _loss: f32) -> Result<()> {
+        Ok(())
+    }
+
+    // What a checkpoint keeps of the callback, handed to `load_state` by `Trainer::resume`
+    // before `on_train_begin`. `None` when there is nothing worth keeping.
+    fn save_state(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+// so a callback can be lent to a trainer and looked at afterwards
+impl<C: Callback + ?Sized> Callback for &mut C {
+    fn on_train_begin(&mut self, ctx: &mut Context) -> Result<()> {
+        (**self).on_train_begin(ctx)
+    }
+
+    fn on_train_end(&mut self, ctx: &mut Context) -> Result<()> {
+        (**self).on_train_end(ctx)
+    }
+
+    fn on_epoch_begin(&mut self, ctx: &mut Context) -> Result<()> {
+        (**self).on_epoch_begin(ctx)
+    }
+
+    fn on_epoch_end(&mut self, ctx: &mut Context, report: &Report) -> Result<()> {
+        (**self).on_epoch_end(ctx, report)
+    }
+
+    fn on_batch_begin(&mut self, ctx: &mut Context) -> Result<()> {
+        (**self).on_batch_begin(ctx)
+    }
+
+    fn on_batch_end(&mut self, ctx: &mut Context, loss: f32) -> Result<()> {
+        (**self).on_batch_end(ctx, loss)
+    }
+
+    fn save_state(&self) -> Result<Option<Vec<u8>>> {
+        (**self).save_state()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        (**self).load_state(state)
+    }
+}
+
+struct EpochEnd<F>(F);
+
+impl<F: FnMut(&Report, &Network) -> Result<()>> Callback for EpochEnd<F> {
+    fn on_epoch_end(&mut self, ctx: &mut Context, report: &Report) -> Result<()> {
+        (self.0)(report, ctx.network)
+    }
+}
+
+// A callback running `f` with the report of every epoch and the network after it.
+pub fn on_epoch_end(f: impl FnMut(&Report, &Network) -> Result<()>) -> impl Callback {
+    EpochEnd(f)
+}
+
+// Prints the report of every epoch, and the mean loss of every `every` batches if set.
+#[derive(Default)]
+pub struct Progress {
+    every: Option<usize>,
+    loss: f32,
+    batches: usize,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn every(self, batches: usize) -> Self {
+        Self {
+            every: Some(batches.max(1)),
+            ..self
+        }
+    }
+}
+
+impl Callback for Progress {
+    fn on_batch_end(&mut self, ctx: &mut Context, loss: f32) -> Result<()> {
+        let Some(every) = self.every else {
+            return Ok(());
+        };
+        self.loss += loss;
+        self.batches += 1;
+        if self.batches == every || ctx.batch + 1 == ctx.batches {
+            println!(
+                "epoch: {} batch: {}/{} loss: {}",
+                ctx.epoch + 1,
+                ctx.batch + 1,
+                ctx.batches,
+                self.loss / self.batches as f32
+            );
+            self.loss = 0f32;
+            self.batches = 0;
+        }
+        Ok(())
+    }
+
+    fn on_epoch_end(&mut self, _ctx: &mut Context, report: &Report) -> Result<()> {
+        println!("{}", report);
+        Ok(())
+    }
+}
+
+// Writes one line per epoch to a CSV file: epoch, loss, then the validation loss and metrics
+// when there are any. The file is created when training begins, or appended to when it resumes,
+// and flushed after every line.
+pub struct CsvLogger {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    // whether the header still has to go before the next line
+    header: bool,
+}
+
+impl CsvLogger {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            file: None,
+            header: false,
+        }
+    }
+}
+
+impl Callback for CsvLogger {
+    fn on_train_begin(&mut self, ctx: &mut Context) -> Result<()> {
+        let file = if ctx.epoch > 0 {
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.path)?
+        } else {
+            File::create(&self.path)?
+        };
+        self.header = file.metadata()?.len() == 0;
+        self.file = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    fn on_epoch_end(&mut self, _ctx: &mut Context, report: &Report) -> Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        // the columns are known once the first report is in
+        if std::mem::take(&mut self.header) {
+            write!(file, "epoch,loss")?;
+            if report.val_loss.is_some() {
+                write!(file, ",val_loss")?;
+            }
+            for (metric, _) in report.val_metrics.iter() {
+                write!(file, ",val_{}", metric.name())?;
+            }
+            writeln!(file)?;
+        }
+        write!(file, "{},{}", report.epoch + 1, report.loss)?;
+        if let Some(val_loss) = report.val_loss {
+            write!(file, ",{}", val_loss)?;
+        }
+        for (_, score) in report.val_metrics.iter() {
+            write!(file, ",{}", score)?;
+        }
+        writeln!(file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn on_train_end(&mut self, _ctx: &mut Context) -> Result<()> {
+        self.file = None;
+        Ok(())
+    }
+}
+
+// Saves the network to `path` after every epoch, see `Network::save_to_file`. To be able to
+// resume training, see `Checkpointer`.
+pub struct SaveNetwork {
+    path: PathBuf,
+}
+
+impl SaveNetwork {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Callback for SaveNetwork {
+    fn on_epoch_end(&mut self, ctx: &mut Context, _report: &Report) -> Result<()> {
+        ctx.network.save_to_file(&self.path)
+    }
+}
+
+// How `LrScheduler` changes the learning rate after every epoch, starting from the rate the
+// optimizer had when the run began.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Schedule {
+    // times `gamma` every `every` epochs
+    Step {
+        every: usize,
+        gamma: f32,
+    },
+    // times `gamma` every epoch
+    Exponential {
+        gamma: f32,
+    },
+    // from the initial rate down to `min` over `epochs` epochs along half a cosine
+    Cosine {
+        epochs: usize,
+        min: f32,
+    },
+    // times `factor` when `monitor` did not improve for `patience` epochs, never below `min`
+    OnPlateau {
+        monitor: Monitor,
+        factor: f32,
+        patience: usize,
+        min: f32,
+    },
+}
+
+pub struct LrScheduler {
+    schedule: Schedule,
+    // for `OnPlateau`: the best value so far and the epochs since
+    best: Option<f32>,
+    waited: usize,
+}
+
+impl LrScheduler {
+    pub fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule,
+            best: None,
+            waited: 0,
+        }
+    }
+}
+
+impl Callback for LrScheduler {
+    // a resumed run keeps what `load_state` restored
+    fn on_train_begin(&mut self, ctx: &mut Context) -> Result<()> {
+        if ctx.epoch == 0 {
+            self.best = None;
+            self.waited = 0;
+        }
+        Ok(())
+    }
+
+    fn save_state(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(serde_cbor::to_vec(&(self.best, self.waited))?))
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        (self.best, self.waited) = serde_cbor::from_slice(state)?;
+        Ok(())
+    }
+
+    fn on_epoch_end(&mut self, ctx: &mut Context, report: &Report) -> Result<()> {
+        // the rate for the next epoch
+        let done = ctx.epoch + 1;
+        let initial = ctx.initial_learning_rate;
+        let learning_rate = match self.schedule {
+            Schedule::Step { every, gamma } => initial * gamma.powi((done / every.max(1)) as i32),
+            Schedule::Exponential { gamma } => initial * gamma.powi(done as i32),
+            Schedule::Cosine { epochs, min } => {
+                let progress = done.min(epochs) as f32 / epochs.max(1) as f32;
+                min + (initial - min) * (1f32 + (PI * progress).cos()) / 2f32
+            }
+            Schedule::OnPlateau {
+                monitor,
+                factor,
+                patience,
+                min,
+            } => {
+                let value = report.get(monitor).ok_or_else(|| {
+                    Error::InvalidConfig(format!("{:?} is not in the epoch report", monitor))
+                })?;
+                let current = ctx.optimizer.learning_rate();
+                match self.best {
+                    Some(best) if !monitor.improves(value, best, 0f32) => {
+                        self.waited += 1;
+                        if self.waited > patience {
+                            self.waited = 0;
+                            (current * factor).max(min)
+                        } else {
+                            current
+                        }
+                    }
+                    _ => {
+                        self.best = Some(value);
+                        self.waited = 0;
+                        current
+                    }
+                }
+            }
+        };
+        ctx.optimizer.set_learning_rate(learning_rate);
+        Ok(())
+    }
+}
+
+#[test]
+fn schedules_change_the_learning_rate() {
+    use crate::optim::SGD;
+
+    let network = Network::default();
+    let mut optimizer = SGD::new(1f32);
+    let report = |epoch, loss| Report {
+        epoch,
+        loss,
+        val_loss: None,
+        val_metrics: Vec::new(),
+    };
+    // the learning rates after each of `losses.len()` epochs
+    let mut rates = |schedule, losses: &[f32]| {
+        let mut scheduler = LrScheduler::new(schedule);
+        optimizer.set_learning_rate(1f32);
+        let mut ctx = Context {
+            network: &network,
+            optimizer: &mut optimizer,
+            epoch: 0,
+            batch: 0,
+            batches: 1,
+            step: 0,
+            initial_learning_rate: 1f32,
+            stop: false,
+        };
+        scheduler.on_train_begin(&mut ctx).unwrap();
+        let mut rates = Vec::new();
+        for (epoch, loss) in losses.iter().enumerate() {
+            ctx.epoch = epoch;
+            scheduler
+                .on_epoch_end(&mut ctx, &report(epoch, *loss))
+                .unwrap();
+            rates.push(ctx.optimizer.learning_rate());
+        }
+        rates
+    };
+
+    let step = rates(
+        Schedule::Step {
+            every: 2,
+            gamma: 0.5,
+        },
+        &[0f32; 4],
+    );
+    assert_eq!(step, vec![1f32, 0.5, 0.5, 0.25]);
+    let cosine = rates(
+        Schedule::Cosine {
+            epochs: 2,
+            min: 0f32,
+        },
+        &[0f32; 3],
+    );
+    assert!((cosine[0] - 0.5).abs() < 1e-6 && cosine[1].abs() < 1e-6 && cosine[2].abs() < 1e-6);
+    let plateau = rates(
+        Schedule::OnPlateau {
+            monitor: Monitor::Loss,
+            factor: 0.1,
+            patience: 1,
+            min: 0.05,
+        },
+        &[1f32, 0.5, 0.6, 0.7, 0.8, 0.9, 1f32],
+    );
+    assert_eq!(plateau, vec![1f32, 1f32, 1f32, 0.1, 0.1, 0.05, 0.05]);
+}
@@ -0,0 +1,265 @@
+use crate::error::{Error, Result};
+use crate::metrics::{Monitor, Report};
+use crate::network::Network;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// A snapshot of a training run after an epoch, everything `Trainer::resume` needs to carry on
+// as if the run had never stopped.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub network: Network,
+    // see `Optimizer::save_state`
+    pub optimizer: Vec<u8>,
+    // epochs finished and optimizer steps taken
+    pub epoch: usize,
+    pub step: usize,
+    // the learning rate the run started with, see `Context::initial_learning_rate`
+    pub initial_learning_rate: f32,
+    // the loader's generator, for the order of the next epochs
+    pub(crate) loader_rng: ChaCha8Rng,
+    // see `Callback::save_state`, one per callback in order
+    pub(crate) callbacks: Vec<Option<Vec<u8>>>,
+    // early stopping: the best monitored value with its weights, and the epochs since
+    pub(crate) best: Option<(f32, Option<Network>)>,
+    pub(crate) waited: usize,
+    // the best value `Checkpointer::best` saved
+    pub(crate) saved_best: Option<f32>,
+}
+
+impl Checkpoint {
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut file = fs::File::create(path)?;
+        let serialized: Vec<u8> = serde_cbor::to_vec(&self)?;
+        file.write_all(&serialized)?;
+        Ok(())
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let f = fs::File::open(path)?;
+        let checkpoint: Checkpoint = serde_cbor::from_reader(f)?;
+        Ok(checkpoint)
+    }
+}
+
+// When `Trainer` writes checkpoints into `dir`: `epoch-N.ckpt` after every `every`th epoch,
+// keeping the last `keep_last` of them, and `best.ckpt` whenever `best` improves.
+//
+//     Trainer::new()
+//         .checkpoints(Checkpointer::new("./runs/mnist").keep_last(3).best(Monitor::ValLoss))
+//         .fit(&mut network, CrossEntropy, &mut loader, &mut optimizer, 20)?;
+pub struct Checkpointer {
+    dir: PathBuf,
+    every: usize,
+    keep_last: Option<usize>,
+    best: Option<Monitor>,
+    // the epoch files in `dir`, oldest first, `None` until it was looked at
+    saved: Option<VecDeque<PathBuf>>,
+    pub(crate) saved_best: Option<f32>,
+}
+
+impl Checkpointer {
+    // after every epoch, keeping them all
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            every: 1,
+            keep_last: None,
+            best: None,
+            saved: None,
+            saved_best: None,
+        }
+    }
+
+    // 0 writes no epoch files, e.g. to only keep the best one
+    pub fn every(self, every: usize) -> Self {
+        Self { every, ..self }
+    }
+
+    // Deletes older epoch files, counting the ones already in `dir` from before a restart.
+    pub fn keep_last(self, keep_last: usize) -> Self {
+        Self {
+            keep_last: Some(keep_last),
+            ..self
+        }
+    }
+
+    pub fn best(self, monitor: Monitor) -> Self {
+        Self {
+            best: Some(monitor),
+            ..self
+        }
+    }
+
+    pub(crate) fn monitor(&self) -> Option<Monitor> {
+        self.best
+    }
+
+    // Writes what the policy asks for after the epoch of `report`, `checkpoint` is only made
+    // when something is written.
+    pub(crate) fn epoch_end(
+        &mut self,
+        report: &Report,
+        checkpoint: impl FnOnce() -> Result<Checkpoint>,
+    ) -> Result<()> {
+        let periodic = self.every > 0 && (report.epoch + 1) % self.every == 0;
+        let improved = match self.best {
+            Some(monitor) => {
+                let value = report.get(monitor).ok_or_else(|| {
+                    Error::InvalidConfig(format!("{:?} is not in the epoch report", monitor))
+                })?;
+                let improved = match self.saved_best {
+                    Some(best) => monitor.improves(value, best, 0f32),
+                    None => !value.is_nan(),
+                };
+                if improved {
+                    self.saved_best = Some(value);
+                }
+                improved
+            }
+            None => false,
+        };
+        if !periodic && !improved {
+            return Ok(());
+        }
+        let mut checkpoint = checkpoint()?;
+        checkpoint.saved_best = self.saved_best;
+        fs::create_dir_all(&self.dir)?;
+        if improved {
+            checkpoint.save_to_file(self.dir.join("best.ckpt"))?;
+        }
+        if periodic {
+            let path = self.dir.join(format!("epoch-{}.ckpt", report.epoch + 1));
+            checkpoint.save_to_file(&path)?;
+            let saved = match self.saved.take() {
+                Some(saved) => saved,
+                None => self.existing()?,
+            };
+            let saved = self.saved.insert(saved);
+            saved.retain(|p| *p != path);
+            saved.push_back(path);
+            while saved.len() > self.keep_last.unwrap_or(usize::MAX) {
+                if let Some(old) = saved.pop_front() {
+                    fs::remove_file(old)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the `epoch-N.ckpt` files in `dir`, by epoch
+    fn existing(&self) -> Result<VecDeque<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let epoch = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("epoch-")?.strip_suffix(".ckpt"))
+                .and_then(|epoch| epoch.parse::<usize>().ok());
+            if let Some(epoch) = epoch {
+                files.push((epoch, path));
+            }
+        }
+        files.sort();
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+#[test]
+fn resumed_training_matches_an_uninterrupted_run() {
+    use crate::activations::{ActivationFn, Sigmoid, Tanh};
+    use crate::callbacks::{CsvLogger, LrScheduler, Schedule};
+    use crate::data::{DataLoader, InMemory};
+    use crate::loss::MSE;
+    use crate::optim::Adam;
+    use crate::trainer::Trainer;
+
+    let xs: Vec<Vec<f32>> = (0..23)
+        .map(|i| vec![(i as f32 * 0.7).sin(), (i % 3) as f32 / 3f32])
+        .collect();
+    let ys: Vec<Vec<f32>> = (0..23).map(|i| vec![(i % 2) as f32]).collect();
+    let dataset = InMemory::new(xs, ys).unwrap();
+    let network = |seed| {
+        Network::builder(2)
+            .seed(seed)
+            .dense(4)
+            .activation(ActivationFn::Tanh(Tanh::default()))
+            .dense(1)
+            .activation(ActivationFn::Sigmoid(Sigmoid::default()))
+            .build()
+            .unwrap()
+    };
+    let dir = std::env::temp_dir().join(format!("checkpoints-{}", std::process::id()));
+    // the scheduler only resumes right with its state from the checkpoint
+    let train = |trainer: Trainer, network: &mut Network, epochs| {
+        let plateau = Schedule::OnPlateau {
+            monitor: Monitor::Loss,
+            factor: 0.5,
+            patience: 0,
+            min: 0f32,
+        };
+        let mut trainer = trainer
+            .callback(LrScheduler::new(plateau))
+            .threads(2)
+            .seed(9);
+        let mut loader = DataLoader::new(&dataset, 5).shuffle(true);
+        trainer
+            .fit(network, MSE, &mut loader, &mut Adam::new(0.05), epochs)
+            .unwrap();
+    };
+
+    let mut uninterrupted = network(1);
+    train(Trainer::new(), &mut uninterrupted, 5);
+
+    let mut interrupted = network(1);
+    let checkpointer = Checkpointer::new(&dir).keep_last(2).best(Monitor::Loss);
+    let csv = dir.join("log.csv");
+    fs::create_dir_all(&dir).unwrap();
+    train(
+        Trainer::new()
+            .checkpoints(checkpointer)
+            .callback(CsvLogger::new(&csv)),
+        &mut interrupted,
+        3,
+    );
+    assert!(!dir.join("epoch-1.ckpt").exists());
+    assert!(dir.join("epoch-3.ckpt").exists() && dir.join("best.ckpt").exists());
+
+    // picked up after epoch 3 with another network, optimizer and loader order, the log goes on
+    // and the files from before the restart count towards `keep_last`
+    let checkpoint = Checkpoint::from_file(dir.join("epoch-3.ckpt")).unwrap();
+    assert_eq!((checkpoint.epoch, checkpoint.step), (3, 15));
+    let mut resumed = network(2);
+    train(
+        Trainer::new()
+            .resume(checkpoint)
+            .checkpoints(Checkpointer::new(&dir).keep_last(2))
+            .callback(CsvLogger::new(&csv)),
+        &mut resumed,
+        5,
+    );
+    let kept = [
+        "epoch-2.ckpt",
+        "epoch-3.ckpt",
+        "epoch-4.ckpt",
+        "epoch-5.ckpt",
+    ]
+    .map(|name| dir.join(name).exists());
+    let log = fs::read_to_string(&csv).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let epochs: Vec<&str> = log
+        .lines()
+        .map(|line| &line[..line.find(',').unwrap()])
+        .collect();
+    assert_eq!(epochs, vec!["epoch", "1", "2", "3", "4", "5"]);
+    assert_eq!(kept, [false, false, true, true]);
+    assert_eq!(
+        serde_cbor::to_vec(&resumed).unwrap(),
+        serde_cbor::to_vec(&uninterrupted).unwrap()
+    );
+}
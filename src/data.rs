@@ -0,0 +1,340 @@
+use crate::error::{Error, Result};
+use crate::rng;
+use crate::tensor::Tensor;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+
+// (input, target) samples the `DataLoader` reads by index. `get` is called from the loader's
+// worker threads, hence `Sync`.
+pub trait Dataset: Sync {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, index: usize) -> Result<(Vec<f32>, Vec<f32>)>;
+}
+
+// so a loader can borrow a dataset instead of owning it
+impl<D: Dataset + ?Sized> Dataset for &D {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get(&self, index: usize) -> Result<(Vec<f32>, Vec<f32>)> {
+        (**self).get(index)
+    }
+}
+
+// Samples already in memory, one Vec per input and target.
+pub struct InMemory {
+    inputs: Vec<Vec<f32>>,
+    targets: Vec<Vec<f32>>,
+}
+
+impl InMemory {
+    pub fn new(inputs: Vec<Vec<f32>>, targets: Vec<Vec<f32>>) -> Result<Self> {
+        if inputs.len() != targets.len() {
+            return Err(Error::InvalidConfig(format!(
+                "{} inputs but {} targets",
+                inputs.len(),
+                targets.len()
+            )));
+        }
+        Ok(Self { inputs, targets })
+    }
+}
+
+impl Dataset for InMemory {
+    fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    fn get(&self, index: usize) -> Result<(Vec<f32>, Vec<f32>)> {
+        Ok((self.inputs[index].clone(), self.targets[index].clone()))
+    }
+}
+
+// Samples made by `load` when they are needed, e.g. read from disk or decoded from a compressed
+// archive. Nothing is cached.
+pub struct Lazy<F> {
+    len: usize,
+    load: F,
+}
+
+impl<F> Lazy<F>
+where
+    F: Fn(usize) -> Result<(Vec<f32>, Vec<f32>)> + Sync,
+{
+    pub fn new(len: usize, load: F) -> Self {
+        Self { len, load }
+    }
+}
+
+impl<F> Dataset for Lazy<F>
+where
+    F: Fn(usize) -> Result<(Vec<f32>, Vec<f32>)> + Sync,
+{
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, index: usize) -> Result<(Vec<f32>, Vec<f32>)> {
+        (self.load)(index)
+    }
+}
+
+// A mini-batch, one sample per row: inputs [batch, input_size], targets [batch, target_size].
+pub struct Batch {
+    pub inputs: Tensor,
+    pub targets: Tensor,
+}
+
+impl Batch {
+    pub fn len(&self) -> usize {
+        self.inputs.shape()[0]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Cuts a dataset into mini-batches, once per epoch.
+//
+//     let mut loader = DataLoader::new(InMemory::new(images, labels)?, 32)
+//         .shuffle(true)
+//         .workers(2);
+//     loader.for_each_batch(|batch| { ...; Ok(true) })?;
+pub struct DataLoader<D> {
+    dataset: D,
+    batch_size: usize,
+    shuffle: bool,
+    drop_last: bool,
+    workers: usize,
+    pub(crate) rng: ChaCha8Rng,
+    // whether `rng` came from `seed`, which `Trainer::seed` then leaves alone
+    pub(crate) seeded: bool,
+}
+
+// how many batches each worker may load ahead of the training loop
+const PREFETCH: usize = 2;
+
+impl<D: Dataset> DataLoader<D> {
+    // in order, keeping the last smaller batch, loading on the calling thread
+    pub fn new(dataset: D, batch_size: usize) -> Self {
+        Self {
+            dataset,
+            batch_size,
+            shuffle: false,
+            drop_last: false,
+            workers: 0,
+            rng: rng::rng(),
+            seeded: false,
+        }
+    }
+
+    // A new random order every epoch.
+    pub fn shuffle(self, shuffle: bool) -> Self {
+        Self { shuffle, ..self }
+    }
+
+    // Skips the last batch of an epoch when there are fewer than `batch_size` samples left.
+    pub fn drop_last(self, drop_last: bool) -> Self {
+        Self { drop_last, ..self }
+    }
+
+    // Loads batches on `workers` background threads while the current one trains, 0 loads them
+    // on the calling thread. Batches come out in the same order either way.
+    pub fn workers(self, workers: usize) -> Self {
+        Self { workers, ..self }
+    }
+
+    // The generator the order is shuffled with, `rng::rng()` by default.
+    pub fn seed(self, seed: u64) -> Self {
+        Self {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            seeded: true,
+            ..self
+        }
+    }
+
+    pub fn dataset(&self) -> &D {
+        &self.dataset
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    // the number of batches in an epoch
+    pub fn len(&self) -> usize {
+        if self.drop_last {
+            self.dataset.len() / self.batch_size.max(1)
+        } else {
+            self.dataset.len().div_ceil(self.batch_size.max(1))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Runs `f` on every batch of one epoch in order, stopping at the first error or when `f`
+    // returns false.
+    pub fn for_each_batch(&mut self, mut f: impl FnMut(Batch) -> Result<bool>) -> Result<()> {
+        if self.batch_size == 0 {
+            return Err(Error::InvalidConfig("batch size has to be positive".into()));
+        }
+        let mut order: Vec<usize> = (0..self.dataset.len()).collect();
+        if self.shuffle {
+            order.shuffle(&mut self.rng);
+        }
+        let batches: Vec<&[usize]> = order
+            .chunks(self.batch_size)
+            .filter(|batch| !self.drop_last || batch.len() == self.batch_size)
+            .collect();
+        let dataset = &self.dataset;
+        let workers = self.workers;
+        if workers == 0 {
+            for indices in batches {
+                if !f(collate(dataset, indices)?)? {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+
+        // worker w loads batches w, w + workers, ..., so reading the workers round robin gives
+        // the batches back in order
+        thread::scope(|s| {
+            let receivers: Vec<Receiver<Result<Batch>>> = (0..workers)
+                .map(|w| {
+                    let (sender, receiver) = sync_channel(PREFETCH);
+                    let mine: Vec<&[usize]> =
+                        batches.iter().skip(w).step_by(workers).copied().collect();
+                    s.spawn(move || {
+                        for indices in mine {
+                            // the loop stopped early and dropped the receiver
+                            if sender.send(collate(dataset, indices)).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                    receiver
+                })
+                .collect();
+            for b in 0..batches.len() {
+                let batch = receivers[b % workers]
+                    .recv()
+                    .expect("a data loader worker panicked")?;
+                if !f(batch)? {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+// the samples at `indices` stacked into a batch
+fn collate(dataset: &impl Dataset, indices: &[usize]) -> Result<Batch> {
+    let mut inputs = Vec::new();
+    let mut targets = Vec::new();
+    let mut sizes = (0, 0);
+    for (n, &index) in indices.iter().enumerate() {
+        let (input, target) = dataset.get(index)?;
+        if n == 0 {
+            sizes = (input.len(), target.len());
+        }
+        for (expected, found) in [(sizes.0, input.len()), (sizes.1, target.len())] {
+            if expected != found {
+                return Err(Error::ShapeMismatch { expected, found });
+            }
+        }
+        inputs.extend(input);
+        targets.extend(target);
+    }
+    Ok(Batch {
+        inputs: Tensor::new(inputs, &[indices.len(), sizes.0]),
+        targets: Tensor::new(targets, &[indices.len(), sizes.1]),
+    })
+}
+
+#[test]
+fn loader_batches_shuffles_and_prefetches() {
+    let dataset = Lazy::new(10, |i| Ok((vec![i as f32, -(i as f32)], vec![i as f32])));
+    let epoch = |loader: &mut DataLoader<_>| {
+        let mut samples = Vec::new();
+        loader
+            .for_each_batch(|batch| {
+                samples.push(batch.targets.into_vec());
+                Ok(true)
+            })
+            .unwrap();
+        samples
+    };
+
+    let mut in_order = DataLoader::new(&dataset, 4);
+    assert_eq!(in_order.len(), 3);
+    assert_eq!(
+        epoch(&mut in_order),
+        vec![
+            vec![0f32, 1f32, 2f32, 3f32],
+            vec![4f32, 5f32, 6f32, 7f32],
+            vec![8f32, 9f32]
+        ]
+    );
+    let mut dropping = DataLoader::new(&dataset, 4).drop_last(true);
+    assert_eq!(dropping.len(), 2);
+    assert_eq!(epoch(&mut dropping).len(), 2);
+
+    // a new order every epoch, the same orders for the same seed, with or without workers
+    let mut shuffled = DataLoader::new(&dataset, 3).shuffle(true).seed(7);
+    let mut prefetched = DataLoader::new(&dataset, 3)
+        .shuffle(true)
+        .seed(7)
+        .workers(3);
+    let first = epoch(&mut shuffled);
+    let second = epoch(&mut shuffled);
+    assert_ne!(first, second);
+    assert_eq!(epoch(&mut prefetched), first);
+    assert_eq!(epoch(&mut prefetched), second);
+    let mut seen: Vec<f32> = first.concat();
+    seen.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(seen, (0..10).map(|i| i as f32).collect::<Vec<_>>());
+
+    // errors from the dataset and from `f` stop the epoch, and so does `f` returning false
+    let broken = Lazy::new(5, |i| {
+        if i == 3 {
+            Err(Error::InvalidConfig("unreadable sample".into()))
+        } else {
+            Ok((vec![0f32], vec![0f32]))
+        }
+    });
+    let mut loader = DataLoader::new(broken, 2).workers(2);
+    assert!(matches!(
+        loader.for_each_batch(|_| Ok(true)),
+        Err(Error::InvalidConfig(_))
+    ));
+    let mut loader = DataLoader::new(&dataset, 2).workers(2);
+    let mut calls = 0;
+    let stopped = loader.for_each_batch(|_| {
+        calls += 1;
+        Err(Error::NaN { epoch: 0 })
+    });
+    assert!(matches!(stopped, Err(Error::NaN { .. })));
+    assert_eq!(calls, 1);
+    calls = 0;
+    loader
+        .for_each_batch(|_| {
+            calls += 1;
+            Ok(calls < 2)
+        })
+        .unwrap();
+    assert_eq!(calls, 2);
+}
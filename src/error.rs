@@ -0,0 +1,64 @@
+use crate::builder::BuildError;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    // an input (or target) whose size doesn't match what the network takes (or outputs)
+    ShapeMismatch { expected: usize, found: usize },
+    // an inconsistent architecture, see `NetworkBuilder`
+    Build(BuildError),
+    Io(std::io::Error),
+    Serialization(serde_cbor::Error),
+    // arguments that can't work together, e.g. a batch size of 0 or no training samples
+    InvalidConfig(String),
+    // the training loss became NaN or infinite, usually a too high learning rate
+    NaN { epoch: usize },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ShapeMismatch { expected, found } => {
+                write!(f, "expected {} values, found {}", expected, found)
+            }
+            Error::Build(e) => write!(f, "invalid network: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Serialization(e) => write!(f, "serialization error: {}", e),
+            Error::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            Error::NaN { epoch } => {
+                write!(f, "loss became NaN or infinite during epoch {}", epoch + 1)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Build(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(e: BuildError) -> Self {
+        Error::Build(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_cbor::Error> for Error {
+    fn from(e: serde_cbor::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
@@ -0,0 +1,275 @@
+// Cache blocked matrix multiply. Blocks of both operands are packed into contiguous panels so the
+// micro kernel streams through memory in order, whatever the strides of the inputs (transposed
+// views included). The micro kernel uses AVX2 / NEON when the CPU has it and plain scalar code
+// otherwise.
+use std::cell::RefCell;
+
+// micro kernel tile: MR rows of a times NR columns of b
+const MR: usize = 4;
+const NR: usize = 16;
+// blocks of a are MC x KC (fits in L2), panels of b are KC x NC
+const MC: usize = 64;
+const KC: usize = 256;
+const NC: usize = 2048;
+// below this many multiply-adds packing costs more than it saves
+const SMALL: usize = 16 * 1024;
+
+// A matrix stored in a slice, element (i, j) is at `i * row_stride + j * col_stride`.
+#[derive(Clone, Copy)]
+pub(crate) struct Matrix<'a> {
+    pub data: &'a [f32],
+    pub rows: usize,
+    pub cols: usize,
+    pub row_stride: usize,
+    pub col_stride: usize,
+}
+
+impl Matrix<'_> {
+    fn at(&self, i: usize, j: usize) -> f32 {
+        self.data[i * self.row_stride + j * self.col_stride]
+    }
+}
+
+thread_local! {
+    // packed blocks of a and b, kept between calls so repeated products don't allocate
+    static PACKED: RefCell<(Vec<f32>, Vec<f32>)> = const { RefCell::new((Vec::new(), Vec::new())) };
+}
+
+// c += a x b, with `c` a contiguous [a.rows, b.cols] matrix.
+pub(crate) fn gemm(a: Matrix, b: Matrix, c: &mut [f32]) {
+    gemm_with(Kernel::detect(), a, b, c)
+}
+
+fn gemm_with(kernel: Kernel, a: Matrix, b: Matrix, c: &mut [f32]) {
+    let (m, k, n) = (a.rows, a.cols, b.cols);
+    assert_eq!(
+        k, b.rows,
+        "cannot multiply [{}, {}] by [{}, {}]",
+        m, k, b.rows, n
+    );
+    assert_eq!(c.len(), m * n);
+    if m * n * k <= SMALL {
+        return naive(a, b, c);
+    }
+    PACKED.with(|packed| {
+        let (packed_a, packed_b) = &mut *packed.borrow_mut();
+        for jc in (0..n).step_by(NC) {
+            let nc = NC.min(n - jc);
+            for pc in (0..k).step_by(KC) {
+                let kc = KC.min(k - pc);
+                pack_b(b, pc, kc, jc, nc, packed_b);
+                for ic in (0..m).step_by(MC) {
+                    let mc = MC.min(m - ic);
+                    pack_a(a, ic, mc, pc, kc, packed_a);
+                    for jr in (0..nc).step_by(NR) {
+                        let nr = NR.min(nc - jr);
+                        let b_panel = &packed_b[jr * kc..(jr + NR) * kc];
+                        for ir in (0..mc).step_by(MR) {
+                            let mr = MR.min(mc - ir);
+                            let a_panel = &packed_a[ir * kc..(ir + MR) * kc];
+                            let mut tile = [[0f32; NR]; MR];
+                            kernel.run(kc, a_panel, b_panel, &mut tile);
+                            // the tile is padded with zeros past the edges of c
+                            for (r, tile_row) in tile.iter().enumerate().take(mr) {
+                                let start = (ic + ir + r) * n + jc + jr;
+                                for (o, t) in c[start..start + nr].iter_mut().zip(tile_row) {
+                                    *o += t;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+// The rows [ic, ic + mc) x columns [pc, pc + kc) of `a` as consecutive MR row panels, each
+// stored column by column: panel[p * MR + r]. Rows past the end are zero.
+fn pack_a(a: Matrix, ic: usize, mc: usize, pc: usize, kc: usize, packed: &mut Vec<f32>) {
+    let len = mc.div_ceil(MR) * MR * kc;
+    if packed.len() < len {
+        packed.resize(len, 0f32);
+    }
+    for ir in (0..mc).step_by(MR) {
+        let panel = &mut packed[ir * kc..(ir + MR) * kc];
+        for p in 0..kc {
+            for r in 0..MR {
+                panel[p * MR + r] = if ir + r < mc {
+                    a.at(ic + ir + r, pc + p)
+                } else {
+                    0f32
+                };
+            }
+        }
+    }
+}
+
+// The rows [pc, pc + kc) x columns [jc, jc + nc) of `b` as consecutive NR column panels, each
+// stored row by row: panel[p * NR + c]. Columns past the end are zero.
+fn pack_b(b: Matrix, pc: usize, kc: usize, jc: usize, nc: usize, packed: &mut Vec<f32>) {
+    let len = nc.div_ceil(NR) * NR * kc;
+    if packed.len() < len {
+        packed.resize(len, 0f32);
+    }
+    for jr in (0..nc).step_by(NR) {
+        let panel = &mut packed[jr * kc..(jr + NR) * kc];
+        for p in 0..kc {
+            for c in 0..NR {
+                panel[p * NR + c] = if jr + c < nc {
+                    b.at(pc + p, jc + jr + c)
+                } else {
+                    0f32
+                };
+            }
+        }
+    }
+}
+
+// Straight triple loop, for products too small to be worth packing.
+fn naive(a: Matrix, b: Matrix, c: &mut [f32]) {
+    let n = b.cols;
+    for i in 0..a.rows {
+        let c_row = &mut c[i * n..(i + 1) * n];
+        for p in 0..a.cols {
+            let a = a.at(i, p);
+            for (j, o) in c_row.iter_mut().enumerate() {
+                *o += a * b.at(p, j);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kernel {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            return Kernel::Avx2;
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return Kernel::Neon;
+        }
+        Kernel::Scalar
+    }
+
+    // tile = a_panel x b_panel over `kc`, see `pack_a` / `pack_b` for the layouts
+    fn run(self, kc: usize, a: &[f32], b: &[f32], tile: &mut [[f32; NR]; MR]) {
+        assert!(a.len() >= kc * MR && b.len() >= kc * NR);
+        match self {
+            Kernel::Scalar => scalar_kernel(kc, a, b, tile),
+            // SAFETY: `detect` only picks the kernel when the CPU supports its features, and the
+            // panels were checked to hold `kc` steps above
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => unsafe { avx2_kernel(kc, a, b, tile) },
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => unsafe { neon_kernel(kc, a, b, tile) },
+        }
+    }
+}
+
+fn scalar_kernel(kc: usize, a: &[f32], b: &[f32], tile: &mut [[f32; NR]; MR]) {
+    for (a, b) in a.chunks_exact(MR).zip(b.chunks_exact(NR)).take(kc) {
+        for (row, a) in tile.iter_mut().zip(a) {
+            for (t, b) in row.iter_mut().zip(b) {
+                *t += a * b;
+            }
+        }
+    }
+}
+
+// 4 rows x 2 registers of 8 lanes kept in registers for the whole panel
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn avx2_kernel(kc: usize, a: &[f32], b: &[f32], tile: &mut [[f32; NR]; MR]) {
+    use std::arch::x86_64::*;
+
+    let mut acc = [[_mm256_setzero_ps(); 2]; MR];
+    for p in 0..kc {
+        let b_row = b.as_ptr().add(p * NR);
+        let b0 = _mm256_loadu_ps(b_row);
+        let b1 = _mm256_loadu_ps(b_row.add(8));
+        for (r, acc) in acc.iter_mut().enumerate() {
+            let a = _mm256_set1_ps(*a.get_unchecked(p * MR + r));
+            acc[0] = _mm256_fmadd_ps(a, b0, acc[0]);
+            acc[1] = _mm256_fmadd_ps(a, b1, acc[1]);
+        }
+    }
+    for (row, acc) in tile.iter_mut().zip(acc) {
+        _mm256_storeu_ps(row.as_mut_ptr(), acc[0]);
+        _mm256_storeu_ps(row.as_mut_ptr().add(8), acc[1]);
+    }
+}
+
+// 4 rows x 4 registers of 4 lanes kept in registers for the whole panel
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn neon_kernel(kc: usize, a: &[f32], b: &[f32], tile: &mut [[f32; NR]; MR]) {
+    use std::arch::aarch64::*;
+
+    let mut acc = [[vdupq_n_f32(0f32); 4]; MR];
+    for p in 0..kc {
+        let b_row = b.as_ptr().add(p * NR);
+        let b = [
+            vld1q_f32(b_row),
+            vld1q_f32(b_row.add(4)),
+            vld1q_f32(b_row.add(8)),
+            vld1q_f32(b_row.add(12)),
+        ];
+        for (r, acc) in acc.iter_mut().enumerate() {
+            let a = vdupq_n_f32(*a.get_unchecked(p * MR + r));
+            for (acc, b) in acc.iter_mut().zip(b) {
+                *acc = vfmaq_f32(*acc, a, b);
+            }
+        }
+    }
+    for (row, acc) in tile.iter_mut().zip(acc) {
+        for (q, acc) in acc.into_iter().enumerate() {
+            vst1q_f32(row.as_mut_ptr().add(q * 4), acc);
+        }
+    }
+}
+
+#[test]
+fn gemm_matches_naive() {
+    let data: Vec<f32> = (0..300 * 300).map(|i| (i as f32 * 0.013).sin()).collect();
+    let mut kernels = vec![Kernel::Scalar];
+    if Kernel::detect() != Kernel::Scalar {
+        kernels.push(Kernel::detect());
+    }
+    // sizes across the block and tile edges, with a row major and a transposed operand
+    for (m, k, n) in [(1, 300, 70), (37, 300, 290), (64, 257, 17), (130, 5, 300)] {
+        let a = Matrix {
+            data: &data,
+            rows: m,
+            cols: k,
+            row_stride: k,
+            col_stride: 1,
+        };
+        let b = Matrix {
+            data: &data,
+            rows: k,
+            cols: n,
+            row_stride: 1,
+            col_stride: k,
+        };
+        let mut expected = vec![1f32; m * n];
+        naive(a, b, &mut expected);
+        for kernel in kernels.iter() {
+            let mut c = vec![1f32; m * n];
+            gemm_with(*kernel, a, b, &mut c);
+            for (x, y) in c.iter().zip(&expected) {
+                assert!((x - y).abs() < 1e-3, "{:?} {:?}", kernel, (m, k, n));
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::activations::ActivationFn;
+use crate::error::{Error, Result};
+use crate::tensor::Tensor;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+
+// How the weights of a layer are drawn. The scaled schemes work out fan in / fan out from the
+// shape of the weights: [outputs, inputs, ...kernel] for both dense layers and convolutions.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum Initializer {
+    Zeros,
+    Constant(f32),
+    Uniform {
+        low: f32,
+        high: f32,
+    },
+    Normal {
+        mean: f32,
+        std: f32,
+    },
+    // Glorot, keeps the variance of activations and gradients for tanh / sigmoid
+    #[default]
+    XavierUniform,
+    XavierNormal,
+    // Kaiming, for the rectifier family
+    HeUniform,
+    HeNormal,
+    // for self normalizing networks (Selu)
+    LeCunUniform,
+    LeCunNormal,
+    // the weights as a matrix [outputs, everything else] with orthonormal rows (or columns when
+    // there are more outputs than inputs), times `gain`
+    Orthogonal {
+        gain: f32,
+    },
+}
+
+impl Initializer {
+    // The usual choice for a layer followed by `activation`.
+    pub fn for_activation(activation: &ActivationFn) -> Self {
+        match activation {
+            ActivationFn::Relu(_)
+            | ActivationFn::LeakyRelu(_)
+            | ActivationFn::PRelu(_)
+            | ActivationFn::Elu(_)
+            | ActivationFn::Gelu(_)
+            | ActivationFn::Silu(_)
+            | ActivationFn::Swish(_)
+            | ActivationFn::Mish(_)
+            | ActivationFn::Softplus(_) => Initializer::HeNormal,
+            ActivationFn::Selu(_) => Initializer::LeCunNormal,
+            ActivationFn::Tanh(_)
+            | ActivationFn::Sigmoid(_)
+            | ActivationFn::Softmax(_)
+            | ActivationFn::LogSoftmax(_)
+            | ActivationFn::HardSigmoid(_)
+            | ActivationFn::HardTanh(_)
+            | ActivationFn::Identity(_) => Initializer::XavierUniform,
+        }
+    }
+
+    // Fails for parameters nothing can be drawn from: a `Uniform` needs finite `low < high`.
+    pub fn check(&self) -> Result<()> {
+        match *self {
+            Initializer::Uniform { low, high }
+                if !(low.is_finite() && high.is_finite()) || low >= high =>
+            {
+                Err(Error::InvalidConfig(format!(
+                    "uniform initializer needs finite low < high, got {} and {}",
+                    low, high
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn tensor<R: Rng + ?Sized>(&self, shape: &[usize], rng: &mut R) -> Result<Tensor> {
+        self.check()?;
+        Ok(self.draw(shape, rng))
+    }
+
+    // `tensor` for an initializer that passed `check`
+    pub(crate) fn draw<R: Rng + ?Sized>(&self, shape: &[usize], rng: &mut R) -> Tensor {
+        let (fan_in, fan_out) = fans(shape);
+        let uniform = |rng: &mut R, limit: f32| rng.gen_range(-limit..=limit);
+        match *self {
+            Initializer::Zeros => Tensor::zeros(shape),
+            Initializer::Constant(value) => Tensor::full(shape, value),
+            Initializer::Uniform { low, high } => {
+                Tensor::from_fn(shape, |_| rng.gen_range(low..high))
+            }
+            Initializer::Normal { mean, std } => {
+                Tensor::from_fn(shape, |_| mean + std * normal(rng))
+            }
+            Initializer::XavierUniform => {
+                let limit = (6f32 / (fan_in + fan_out) as f32).sqrt();
+                Tensor::from_fn(shape, |_| uniform(rng, limit))
+            }
+            Initializer::XavierNormal => {
+                let std = (2f32 / (fan_in + fan_out) as f32).sqrt();
+                Tensor::from_fn(shape, |_| std * normal(rng))
+            }
+            Initializer::HeUniform => {
+                let limit = (6f32 / fan_in as f32).sqrt();
+                Tensor::from_fn(shape, |_| uniform(rng, limit))
+            }
+            Initializer::HeNormal => {
+                let std = (2f32 / fan_in as f32).sqrt();
+                Tensor::from_fn(shape, |_| std * normal(rng))
+            }
+            Initializer::LeCunUniform => {
+                let limit = (3f32 / fan_in as f32).sqrt();
+                Tensor::from_fn(shape, |_| uniform(rng, limit))
+            }
+            Initializer::LeCunNormal => {
+                let std = (1f32 / fan_in as f32).sqrt();
+                Tensor::from_fn(shape, |_| std * normal(rng))
+            }
+            Initializer::Orthogonal { gain } => orthogonal(shape, gain, rng),
+        }
+    }
+}
+
+// (fan in, fan out) of weights shaped [outputs, inputs, ...kernel], a 1d shape counts as both
+fn fans(shape: &[usize]) -> (usize, usize) {
+    let fans = match shape {
+        [] => (1, 1),
+        [size] => (*size, *size),
+        [outputs, inputs, kernel @ ..] => {
+            let receptive: usize = kernel.iter().product();
+            (inputs * receptive, outputs * receptive)
+        }
+    };
+    (fans.0.max(1), fans.1.max(1))
+}
+
+// a standard normal sample (Box-Muller)
+fn normal<R: Rng + ?Sized>(rng: &mut R) -> f32 {
+    let u1 = 1f32 - rng.gen::<f32>(); // (0, 1], keeps ln finite
+    let u2 = rng.gen::<f32>();
+    (-2f32 * u1.ln()).sqrt() * (2f32 * PI * u2).cos()
+}
+
+// Gram-Schmidt on random normal vectors: `short` orthonormal vectors of length `long`, which
+// become the rows or the columns of the [rows, cols] matrix.
+fn orthogonal<R: Rng + ?Sized>(shape: &[usize], gain: f32, rng: &mut R) -> Tensor {
+    let rows = shape.first().copied().unwrap_or(1);
+    let cols = shape.iter().skip(1).product::<usize>();
+    let (short, long) = (rows.min(cols), rows.max(cols));
+    let mut vectors: Vec<Vec<f32>> = Vec::with_capacity(short);
+    while vectors.len() < short {
+        let mut v: Vec<f32> = (0..long).map(|_| normal(rng)).collect();
+        for u in vectors.iter() {
+            let dot: f32 = v.iter().zip(u).map(|(a, b)| a * b).sum();
+            v.iter_mut().zip(u).for_each(|(a, b)| *a -= dot * b);
+        }
+        let norm = v.iter().map(|a| a * a).sum::<f32>().sqrt();
+        // a (numerically) dependent draw, try again
+        if norm < 1e-3 {
+            continue;
+        }
+        v.iter_mut().for_each(|a| *a /= norm);
+        vectors.push(v);
+    }
+    Tensor::from_fn(shape, |i| {
+        let (r, c) = (i / cols, i % cols);
+        let value = if rows <= cols {
+            vectors[r][c]
+        } else {
+            vectors[c][r]
+        };
+        gain * value
+    })
+}
+
+#[test]
+fn initializers_have_the_right_scale() {
+    use crate::activations::{Relu, Selu, Sigmoid};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let std = |t: &Tensor| {
+        let mean = t.sum() / t.len() as f32;
+        let var = t.as_slice().iter().map(|x| (x - mean).powi(2)).sum::<f32>() / t.len() as f32;
+        var.sqrt()
+    };
+    // conv weights: fan in 8 * 5 * 5, fan out 16 * 5 * 5
+    let shape = [16, 8, 5, 5];
+    let xavier = Initializer::XavierUniform.tensor(&shape, &mut rng).unwrap();
+    let limit = (6f32 / 600f32).sqrt();
+    assert!(xavier.as_slice().iter().all(|x| x.abs() <= limit));
+    assert!((std(&xavier) - (2f32 / 600f32).sqrt()).abs() < 0.01);
+    let he = Initializer::HeNormal.tensor(&[300, 400], &mut rng).unwrap();
+    assert!((std(&he) - (2f32 / 400f32).sqrt()).abs() < 0.002);
+    let normal = Initializer::Normal {
+        mean: 3f32,
+        std: 0.5,
+    }
+    .tensor(&[100, 100], &mut rng)
+    .unwrap();
+    assert!((normal.sum() / 10000f32 - 3f32).abs() < 0.05);
+    assert_eq!(
+        Initializer::Constant(0.1)
+            .tensor(&[2, 3], &mut rng)
+            .unwrap(),
+        Tensor::full(&[2, 3], 0.1)
+    );
+
+    // more rows than columns and the other way around: W^T W = I or W W^T = I
+    for shape in [[12, 5], [5, 12]] {
+        let w = Initializer::Orthogonal { gain: 1f32 }
+            .tensor(&shape, &mut rng)
+            .unwrap();
+        let product = if shape[0] > shape[1] {
+            w.t().matmul(&w.view())
+        } else {
+            w.view().matmul(&w.t())
+        };
+        let n = shape[0].min(shape[1]);
+        for i in 0..n {
+            for j in 0..n {
+                let identity = if i == j { 1f32 } else { 0f32 };
+                assert!((product.get(&[i, j]) - identity).abs() < 1e-4);
+            }
+        }
+    }
+
+    for (low, high) in [
+        (1f32, 1f32),
+        (0f32, f32::INFINITY),
+        (f32::NEG_INFINITY, 0f32),
+        (f32::NAN, 1f32),
+    ] {
+        assert!(matches!(
+            Initializer::Uniform { low, high }.tensor(&[2, 2], &mut rng),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    assert_eq!(
+        Initializer::for_activation(&ActivationFn::Relu(Relu::default())),
+        Initializer::HeNormal
+    );
+    assert_eq!(
+        Initializer::for_activation(&ActivationFn::Selu(Selu::default())),
+        Initializer::LeCunNormal
+    );
+    assert_eq!(
+        Initializer::for_activation(&ActivationFn::Sigmoid(Sigmoid::default())),
+        Initializer::XavierUniform
+    );
+}
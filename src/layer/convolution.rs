@@ -1,329 +1,544 @@
-use super::LayerOutput;
-use rand::thread_rng;
+use super::{LayerOutput, Param};
+use crate::error::{Error, Result};
+use crate::gemm::{self, Matrix};
+use crate::init::Initializer;
+use crate::rng;
+use crate::tensor::Tensor;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum Padding {
+    Valid,
+    // pads just enough for the output to be `ceil(input / stride)`, splitting odd padding with
+    // the extra row / column at the bottom / right
+    Same,
+    Explicit(usize),
+}
+
+// What the padded border is filled with.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum PaddingMode {
+    #[default]
+    Zeros,
+    // mirrors the input without repeating the edge: `c b | a b c | b a`
+    Reflect,
+    // repeats the edge: `a a | a b c | c c`
+    Replicate,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub struct ConvOptions {
+    pub stride: usize,
+    pub padding: Padding,
+    pub padding_mode: PaddingMode,
+    pub dilation: usize,
+}
+
+impl Default for ConvOptions {
+    fn default() -> Self {
+        Self {
+            stride: 1,
+            padding: Padding::Valid,
+            padding_mode: PaddingMode::Zeros,
+            dilation: 1,
+        }
+    }
+}
+
+// For every (input channel, kernel row, kernel column) x output position, where that tap reads
+// in a sample, or `PADDED` for zero padding. Built on first use from the layer geometry, so it is
+// neither saved nor compared.
+#[derive(Default, Clone)]
+struct Im2ColTable(OnceLock<Vec<usize>>);
+
+impl PartialEq for Im2ColTable {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+const PADDED: usize = usize::MAX;
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct ConvolutionLayer {
-    pub(crate) input: Vec<Vec<f32>>,
-    pub(crate) input_shape: (usize, usize, usize), // (depth, width, height)
+    // im2col of the input of the last f_prop: [batch, input_depth * size * size, output positions]
+    #[serde(skip)]
+    pub(crate) cols: Tensor,
+    pub(crate) input_shape: (usize, usize, usize), // (depth, height, width)
     pub(crate) output_shape: (usize, usize, usize),
     pub(crate) kernel_shape: (usize, usize), // (depth, size)
-    pub(crate) kernels: Vec<Vec<Vec<f32>>>,  // [ input_depth [ kernel_depth [ kernel ] ] ] = 3d vec
-    pub(crate) biases: Vec<Vec<f32>>,        // 2d vec because u only need one block per depth of kernel,
+    pub(crate) stride: usize,
+    pub(crate) dilation: usize,
+    pub(crate) padding: (usize, usize, usize, usize), // (top, bottom, left, right)
+    pub(crate) padding_mode: PaddingMode,
+    pub(crate) kernels: Tensor, // [kernel_depth, input_depth, size, size]
+    pub(crate) biases: Tensor,  // [kernel_depth], one per output channel
+    #[serde(skip)]
+    pub(crate) kernel_grad: Tensor,
+    #[serde(skip)]
+    pub(crate) bias_grad: Tensor,
+    #[serde(skip)]
+    table: Im2ColTable,
+    // scratch for the gradient of one sample's im2col matrix in b_prop
+    #[serde(skip)]
+    col_grad: Vec<f32>,
 }
 
 impl ConvolutionLayer {
-    pub fn new(input_shape: (usize, usize, usize), kernel_shape: (usize, usize)) -> Self {
+    // stride 1 "valid" correlation
+    pub fn new(input_shape: (usize, usize, usize), kernel_shape: (usize, usize)) -> Result<Self> {
+        Self::with_options(input_shape, kernel_shape, ConvOptions::default())
+    }
+
+    // fails when the kernel does not fit the input
+    pub fn with_options(
+        input_shape: (usize, usize, usize),
+        kernel_shape: (usize, usize),
+        options: ConvOptions,
+    ) -> Result<Self> {
+        Self::with_init(input_shape, kernel_shape, options, Initializer::default())
+    }
+
+    // Zero biases and kernels drawn from `init`. Fails when the kernel does not fit the input or
+    // `init` can't draw, see `Initializer::check`.
+    pub fn with_init(
+        input_shape: (usize, usize, usize),
+        kernel_shape: (usize, usize),
+        options: ConvOptions,
+        init: Initializer,
+    ) -> Result<Self> {
+        Self::with_rng(input_shape, kernel_shape, options, init, &mut rng::rng())
+    }
+
+    pub fn with_rng<R: Rng + ?Sized>(
+        input_shape: (usize, usize, usize),
+        kernel_shape: (usize, usize),
+        options: ConvOptions,
+        init: Initializer,
+        rng: &mut R,
+    ) -> Result<Self> {
+        Self::try_with_options(input_shape, kernel_shape, options, init, rng)
+            .map_err(Error::InvalidConfig)
+    }
+
+    pub(crate) fn try_with_options<R: Rng + ?Sized>(
+        input_shape: (usize, usize, usize),
+        kernel_shape: (usize, usize),
+        options: ConvOptions,
+        init: Initializer,
+        rng: &mut R,
+    ) -> std::result::Result<Self, String> {
+        init.check().map_err(|e| e.to_string())?;
         let (input_depth, input_height, input_width) = input_shape;
         let (kernel_depth, kernel_size) = kernel_shape;
-        assert!(kernel_size > input_depth || kernel_size > input_width);
-        let mut rng = thread_rng();
+        let ConvOptions {
+            stride,
+            padding,
+            padding_mode,
+            dilation,
+        } = options;
+        if kernel_size == 0 || kernel_depth == 0 || stride == 0 || dilation == 0 {
+            return Err("kernel size, kernel depth, stride and dilation must be positive".into());
+        }
+        let span = dilation * (kernel_size - 1) + 1;
 
+        let pad = |input: usize| match padding {
+            Padding::Valid => (0, 0),
+            Padding::Explicit(p) => (p, p),
+            Padding::Same => {
+                let out = input.div_ceil(stride);
+                let total = ((out - 1) * stride + span).saturating_sub(input);
+                (total / 2, total - total / 2)
+            }
+        };
+        let (top, bottom) = pad(input_height);
+        let (left, right) = pad(input_width);
+        if input_height + top + bottom < span || input_width + left + right < span {
+            return Err(format!(
+                "kernel of size {} (dilation {}) does not fit input {:?}",
+                kernel_size, dilation, input_shape
+            ));
+        }
+        if padding_mode == PaddingMode::Reflect
+            && (top.max(bottom) >= input_height || left.max(right) >= input_width)
+        {
+            return Err("reflect padding has to be smaller than the input".into());
+        }
         let output_shape = (
             kernel_depth,
-            input_height - kernel_size + 1,
-            input_width - kernel_size + 1,
+            (input_height + top + bottom - span) / stride + 1,
+            (input_width + left + right - span) / stride + 1,
         );
-        let mut kernels = Vec::with_capacity(input_depth);
-        let mut biases = Vec::with_capacity(input_depth);
-        for _ in 0..input_depth {
-            let mut kernel = Vec::with_capacity(kernel_depth);
-            for _ in 0..kernel_depth {
-                let mut kernel_block = Vec::with_capacity(kernel_size);
-                let mut bias_block = Vec::with_capacity(kernel_size);
-                for _ in 0..kernel_size * kernel_size {
-                    kernel_block.push(rng.gen_range(-1f32..1f32));
-                    bias_block.push(rng.gen_range(-1f32..1f32));
-                }
-                biases.push(bias_block);
-                kernel.push(kernel_block);
-            }
-            kernels.push(kernel);
-        }
-        Self {
-            input: vec![],
+        let kernels = init.draw(&[kernel_depth, input_depth, kernel_size, kernel_size], rng);
+        let biases = Tensor::zeros(&[kernel_depth]);
+        Ok(Self {
+            cols: Tensor::default(),
+            col_grad: Vec::new(),
+            table: Im2ColTable::default(),
             input_shape,
             output_shape,
             kernel_shape,
+            stride,
+            dilation,
+            padding: (top, bottom, left, right),
+            padding_mode,
+            kernel_grad: Tensor::zeros(kernels.shape()),
+            bias_grad: Tensor::zeros(biases.shape()),
             kernels,
             biases,
-        }
+        })
     }
 
-    pub fn f_prop(&mut self, input: &Vec<Vec<f32>>) -> LayerOutput {
-        self.input = input.clone();
-        let mut out: Vec<Vec<f32>> =
-            vec![vec![0f32; self.output_shape.1 * self.output_shape.2]; self.output_shape.0];
-        let kernel_size = self.kernel_shape.1;
-        let chunks = Self::correlation_chunks(&input, &self.input_shape, kernel_size);
-        for (depth, (kernel, chunk)) in self.kernels.iter().zip(chunks).enumerate() {
-            for block in kernel {
-                // chunk is all the movements ( all the slidings ) for the current kernel
-                for (i, mov) in chunk.iter().enumerate() {
-                    out[depth][i] = mov.iter().zip(block).map(|(m, k)| m * k).sum::<f32>();
-                }
-            }
+    // Draws new kernels and zeroes the biases.
+    pub fn initialize<R: Rng + ?Sized>(&mut self, init: Initializer, rng: &mut R) -> Result<()> {
+        self.kernels = init.tensor(self.kernels.shape(), rng)?;
+        self.biases.fill(0f32);
+        Ok(())
+    }
+
+    // The im2col matrices are kept for b_prop, in a buffer reused as long as the batch size
+    // stays the same.
+    pub fn f_prop(&mut self, input: &Tensor) -> LayerOutput {
+        let batch = input.shape()[0];
+        let (out_depth, out_height, out_width) = self.output_shape;
+        let cols_shape = [batch, self.rows(), out_height * out_width];
+        let mut cols = std::mem::take(&mut self.cols);
+        if cols.shape() != cols_shape {
+            cols = Tensor::zeros(&cols_shape);
         }
+        let mut out = Tensor::zeros(&[batch, out_depth, out_height, out_width]);
+        for sample in 0..batch {
+            self.im2col(input.row(sample), cols.row_mut(sample));
+            self.correlate(cols.row(sample), out.row_mut(sample));
+        }
+        self.cols = cols;
         LayerOutput::Conv(out)
     }
 
-    pub fn f_prop_ref(&self, input: &Vec<Vec<f32>>) -> LayerOutput {
-        let mut out: Vec<Vec<f32>> =
-            vec![vec![0f32; self.output_shape.1 * self.output_shape.2]; self.output_shape.0];
-        let kernel_size = self.kernel_shape.1;
-        let chunks = Self::correlation_chunks(&input, &self.input_shape, kernel_size);
-        for (depth, (kernel, chunk)) in self.kernels.iter().zip(chunks).enumerate() {
-            for block in kernel {
-                // chunk is all the movements ( all the slidings ) for the current kernel
-                for (i, mov) in chunk.iter().enumerate() {
-                    out[depth][i] = mov.iter().zip(block).map(|(m, k)| m * k).sum::<f32>();
-                }
-            }
+    // input: [batch, depth, height, width]
+    //
+    // out[o] = bias[o] + sum over every input channel c of correlate(input[c], kernels[o][c]),
+    // done per sample as kernels [out_depth, rows] x im2col(input) [rows, positions]
+    pub fn f_prop_ref(&self, input: &Tensor) -> LayerOutput {
+        let batch = input.shape()[0];
+        let (out_depth, out_height, out_width) = self.output_shape;
+        let mut cols = vec![0f32; self.cols_len()];
+        let mut out = Tensor::zeros(&[batch, out_depth, out_height, out_width]);
+        for sample in 0..batch {
+            self.infer(input.row(sample), out.row_mut(sample), &mut cols);
         }
         LayerOutput::Conv(out)
     }
 
-    pub fn b_prop(&mut self, output_gradient: &Vec<f32>, learning_rate: f32) -> Vec<f32> {
+    // `cols` holds at least `cols_len` floats for the im2col matrix
+    pub(crate) fn infer(&self, input: &[f32], output: &mut [f32], cols: &mut [f32]) {
+        let cols = &mut cols[..self.cols_len()];
+        self.im2col(input, cols);
+        self.correlate(cols, output);
+    }
+
+    pub(crate) fn cols_len(&self) -> usize {
+        self.rows() * self.output_shape.1 * self.output_shape.2
+    }
+
+    // output = biases + kernels [out_depth, rows] x cols [rows, positions] for one sample
+    fn correlate(&self, cols: &[f32], output: &mut [f32]) {
+        let (out_depth, out_height, out_width) = self.output_shape;
+        let out_plane = out_height * out_width;
+        for (plane, b) in output.chunks_mut(out_plane).zip(self.biases.as_slice()) {
+            plane.fill(*b);
+        }
+        let kernels = Matrix {
+            data: self.kernels.as_slice(),
+            rows: out_depth,
+            cols: self.rows(),
+            row_stride: self.rows(),
+            col_stride: 1,
+        };
+        let cols = Matrix {
+            data: cols,
+            rows: self.rows(),
+            cols: out_plane,
+            row_stride: out_plane,
+            col_stride: 1,
+        };
+        gemm::gemm(kernels, cols, output);
+    }
+
+    // output_gradient: [batch, ..]. The gradients of every sample in the batch are summed into
+    // kernel_grad / bias_grad, the kernels themselves are left untouched.
+    pub fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
         // output_gradient =  dE / dY
         //
-        // the output gradient is going to be 1d ( since we flatten our output to 1d array in forward
-        // propagation ), as long as we know our kernel size, we can get the individual block of
-        // output of our kernels.
-        //
-        // since the activation layer store its input as an enum (output of layer), the activation layer
-        // can also decipher the output base on the type of layer it is responsible to. However, we
-        // might not need to do it in the activation layer since we can just flatten the input.
-        let mut og = Vec::new();
-        for chunk in output_gradient
-            .chunks(output_gradient.len() / self.output_shape.0)
-            .into_iter()
+        // the output gradient may come flattened from a dense layer above, as long as we know
+        // the output shape we can get back the plane of every output channel.
+        let batch = output_gradient.shape()[0];
+        let (out_depth, out_height, out_width) = self.output_shape;
+        let out_plane = out_height * out_width;
+        let og = output_gradient
+            .view()
+            .reshape(&[batch, out_depth, out_plane]);
+
+        if self.kernel_grad.shape() != self.kernels.shape()
+            || self.bias_grad.shape() != self.biases.shape()
         {
-            og.push(chunk.to_vec());
+            self.zero_grad();
         }
+        let rows = self.rows();
+        let kernels_t = self.kernels.view().reshape(&[out_depth, rows]).t();
+        self.table();
+        // borrowing the field alone leaves the gradients free to be updated below
+        let table = self.table.0.get().unwrap();
+        let (depth, height, width) = self.input_shape;
+        let mut input_grad = Tensor::zeros(&[batch, depth, height, width]);
+        let mut col_grad = std::mem::take(&mut self.col_grad);
+        col_grad.resize(rows * out_plane, 0f32);
+        for sample in 0..batch {
+            let og_sample = og.at(sample);
+            let cols = self.cols.view().at(sample);
 
-        // cross correlation between output_gradient and input
-        let mut kernel_gradient = vec![vec![]; self.input_shape.0];
-        let kg_chunks =
-            Self::correlation_chunks(&self.input, &self.input_shape, self.output_shape.1);
-        for (depth, (chunk, grad)) in kg_chunks.iter().zip(og.iter()).enumerate() {
-            for mov in chunk {
-                kernel_gradient[depth].push(mov.iter().zip(grad).map(|(m, k)| m * k).sum::<f32>());
+            // dE/dK = og [out_depth, positions] x cols^T [positions, rows]
+            og_sample.matmul_acc(&cols.t(), self.kernel_grad.as_mut_slice());
+            // biases
+            for (b, g) in self
+                .bias_grad
+                .as_mut_slice()
+                .iter_mut()
+                .zip(output_gradient.row(sample).chunks(out_plane))
+            {
+                *b += g.iter().sum::<f32>();
             }
-        }
-        self.kernels = self
-            .kernels
-            .iter()
-            .zip(og.iter())
-            .map(|(kernel_depth, gradients)| {
-                kernel_depth
-                    .iter()
-                    .map(|kernel| {
-                        kernel
-                            .iter()
-                            .zip(gradients.iter())
-                            .map(|(k, g)| k - g * learning_rate)
-                            .collect()
-                    })
-                    .collect()
-            })
-            .collect();
-
-        //biases
-        self.biases = self
-            .biases
-            .iter()
-            //depth
-            .zip(og.iter())
-            //biases and gradients
-            .map(|(bias, grad)| {
-                bias.iter()
-                    .zip(grad.iter())
-                    //bias and grad
-                    .map(|(b, g)| b - g * learning_rate)
-                    .collect()
-            })
-            .collect();
-
-        // Full correlation between output_gradient and kernel
-        //let mut input_grendient = Vec::new();
-        Self::input_gradient(&og, &self.output_shape, &self.kernels, self.kernel_shape.1)
-    }
 
-    fn correlation_chunks(
-        input: &Vec<Vec<f32>>,
-        input_shape: &(usize, usize, usize),
-        size: usize,
-    ) -> Vec<Vec<Vec<f32>>> {
-        let mut out = Vec::new();
-        let mut indices = Vec::new();
-        for _ in 0..input_shape.0 {
-            let mut d = Vec::new();
-            for row in 0..size {
-                for col in 0..size {
-                    d.push(col + row * input_shape.0);
+            // dE/dcols = kernels^T [rows, out_depth] x og [out_depth, positions], then col2im
+            // adds every column back onto the input position it was read from
+            col_grad.fill(0f32);
+            kernels_t.matmul_acc(&og_sample, &mut col_grad);
+            let grad = input_grad.row_mut(sample);
+            for (i, g) in table.iter().zip(&col_grad) {
+                if *i != PADDED {
+                    grad[*i] += g;
                 }
             }
-            indices.push(d);
         }
-        for depth in 0..input_shape.0 {
-            let mut at_depth = Vec::new();
-            for _ in 0..input_shape.2 - size + 1 {
-                for col in 0..input_shape.1 - size + 1 {
-                    let mut tmp = Vec::new();
-                    for i in indices[depth].iter() {
-                        tmp.push(input[depth][*i + col]);
-                    }
-                    at_depth.push(tmp);
-                }
-                indices[depth] = indices[depth].iter().map(|i| i + input_shape.1).collect();
-            }
-            out.push(at_depth);
-        }
-        out
+        self.col_grad = col_grad;
+        input_grad
     }
 
-    fn input_gradient(
-        output_gradient: &Vec<Vec<f32>>,
-        og_shape: &(usize, usize, usize),
-        kernels: &Vec<Vec<Vec<f32>>>,
-        size: usize,
-    ) -> Vec<f32> {
-        let mut col_indices = Vec::new();
-        let mut out_col_indices = Vec::new();
-        let mut tmp = VecDeque::new();
-        let mut out_tmp = VecDeque::new();
-        let mut index = 0;
-        //for _ in 0..input_shape.1 - size + 2 {
-        for _ in 0..og_shape.1 {
-            if tmp.is_empty() || tmp.len() < size {
-                tmp.push_back(index);
-                out_tmp.push_back(index);
-                index += 1;
-            } else if tmp.len() == size {
-                tmp.pop_front().expect("size should not be 0");
-                tmp.push_back(tmp[tmp.len() - 1] + 1);
-            }
-            col_indices.push(tmp.clone());
-            out_col_indices.push(out_tmp.clone());
+    pub fn params(&mut self) -> Vec<Param<'_>> {
+        vec![
+            Param::new(&mut self.kernels, &mut self.kernel_grad),
+            Param::new(&mut self.biases, &mut self.bias_grad),
+        ]
+    }
+
+    // in place once the gradients have their shape, this runs every step
+    pub fn zero_grad(&mut self) {
+        if self.kernel_grad.shape() == self.kernels.shape() {
+            self.kernel_grad.fill(0f32);
+        } else {
+            self.kernel_grad = Tensor::zeros(self.kernels.shape());
         }
-        for _ in 0..2 {
-            tmp.pop_front().expect("size should not be 0");
-            out_tmp.pop_front().expect("size should not be 0");
-            if !tmp.is_empty() {
-                col_indices.push(tmp.clone());
-                out_col_indices.push(out_tmp.clone());
-            }
+        if self.bias_grad.shape() == self.biases.shape() {
+            self.bias_grad.fill(0f32);
+        } else {
+            self.bias_grad = Tensor::zeros(self.biases.shape());
         }
-        let row_indcies: Vec<usize> = col_indices.iter().map(|col| col.len()).collect();
-        let mut output_indices: Vec<Vec<Vec<usize>>> = Vec::new();
-        let mut input_indices: Vec<Vec<Vec<usize>>> = Vec::new();
-        let mut col_idx = 0;
-        let mut out_col_idx = 0;
-        let mut count = 1;
-        let mut out_count = 0;
-        for (row_i, r) in row_indcies.iter().enumerate() {
-            let mut in_tmp: Vec<Vec<usize>> = vec![vec![]; col_indices.len()];
-            let mut out_tmp: Vec<Vec<usize>> = vec![vec![]; col_indices.len()];
-            for _ in 0..*r {
-                for ((input_tmp, output_tmp), (col, out_col)) in in_tmp
-                    .iter_mut()
-                    .zip(out_tmp.iter_mut())
-                    .zip(col_indices.iter().zip(out_col_indices.iter()))
-                {
-                    for c in col {
-                        input_tmp.push(c + og_shape.1 * col_idx);
-                    }
-                    for c in out_col {
-                        output_tmp.push(c + size * out_col_idx);
-                    }
-                }
-                col_idx += 1;
-                out_col_idx += 1;
-            }
-            col_idx = 0;
-            out_col_idx = 0;
-            //println!("{} {}", R, size);
-            if r == &size || count > 1 {
-                col_idx = count;
-                count += 1;
-            }
+    }
 
-            /* if count > 1 && R < &size {
-                out_count += 1;
-                out_col_idx = out_count;
-            } */
+    // Where the (ky, kx) tap of the kernel lands in an input plane for output (oy, ox). `None`
+    // when it falls on zero padding.
+    fn source(&self, oy: usize, ox: usize, ky: usize, kx: usize) -> Option<usize> {
+        let (_, height, width) = self.input_shape;
+        let (top, _, left, _) = self.padding;
+        let y = (oy * self.stride + ky * self.dilation) as isize - top as isize;
+        let x = (ox * self.stride + kx * self.dilation) as isize - left as isize;
+        let y = self.pad_index(y, height)?;
+        let x = self.pad_index(x, width)?;
+        Some(y * width + x)
+    }
 
-            if count > 1 {
-                if r < &size || row_indcies[row_i + 1] < size {
-                    out_count += 1;
-                    out_col_idx = out_count;
-                }
-            }
-            input_indices.push(in_tmp);
-            output_indices.push(out_tmp);
+    fn pad_index(&self, i: isize, len: usize) -> Option<usize> {
+        let last = len as isize - 1;
+        if (0..=last).contains(&i) {
+            return Some(i as usize);
         }
-        /* for i in input_indices.iter() {
-            println!("{:?}", i);
+        match self.padding_mode {
+            PaddingMode::Zeros => None,
+            PaddingMode::Reflect => Some(if i < 0 { -i } else { 2 * last - i } as usize),
+            PaddingMode::Replicate => Some(i.clamp(0, last) as usize),
         }
-        println!("\n");
-        for o in output_indices.iter() {
-            println!("{:?}", o);
-        } */
-        let mut out = Vec::new();
-        // iterating through depth
-        for (og, kernel) in output_gradient.iter().zip(kernels.iter()) {
-            // block: the depth of the input. (vertical). kernel depth is horizontal
-            let mut tmp = Vec::new();
-            for block in kernel {
-                for (ir, or) in input_indices.iter().zip(output_indices.iter()) {
-                    for (ic, oc) in ir.iter().zip(or.iter()) {
-                        let mut row_tmp = Vec::new();
-                        for (i_idx, o_idx) in ic.iter().zip(oc.iter()) {
-                            row_tmp.push(og[*i_idx] * block[*o_idx]);
+    }
+
+    // input_depth * size * size, the rows of the im2col matrix
+    fn rows(&self) -> usize {
+        self.input_shape.0 * self.kernel_shape.1 * self.kernel_shape.1
+    }
+
+    fn table(&self) -> &[usize] {
+        self.table.0.get_or_init(|| {
+            let (depth, height, width) = self.input_shape;
+            let (_, out_height, out_width) = self.output_shape;
+            let size = self.kernel_shape.1;
+            let mut table = Vec::with_capacity(self.rows() * out_height * out_width);
+            for c in 0..depth {
+                for ky in 0..size {
+                    for kx in 0..size {
+                        for oy in 0..out_height {
+                            for ox in 0..out_width {
+                                table.push(
+                                    self.source(oy, ox, ky, kx)
+                                        .map_or(PADDED, |i| c * height * width + i),
+                                );
+                            }
                         }
-                        tmp.push(row_tmp.iter().sum());
                     }
                 }
             }
-            out.extend_from_slice(&tmp);
+            table
+        })
+    }
+
+    // Every kernel sized window the kernel slides over in one sample, as the columns of a
+    // [rows, output positions] matrix.
+    fn im2col(&self, input: &[f32], cols: &mut [f32]) {
+        for (c, i) in cols.iter_mut().zip(self.table()) {
+            *c = if *i == PADDED { 0f32 } else { input[*i] };
         }
-        out
     }
 }
 
 #[test]
 fn conv_init_fb_prop() {
-    let mut l1 = ConvolutionLayer::new((1, 8, 8), (1, 3));
-    let test: Vec<f32> = (0..8 * 8).into_iter().map(|i| i as f32).collect();
-    let l1_out = l1.f_prop(&vec![test]);
-    //println!("{:?}", l1_out);
-
-    let mut l2 = ConvolutionLayer::new((2, 28, 28), (2, 5));
-    let test28: Vec<f32> = (0..28 * 28).into_iter().map(|i| i as f32).collect();
-    let l2_out = l2.f_prop(&vec![test28.clone(), test28.clone()]);
-    //println!("{:?}", l2.f_prop(&vec![test28.clone(), test28.clone()]));
-
-    let mut l3 = ConvolutionLayer::new((3, 28, 28), (3, 5));
-    /* println!(
-        "{:?}",
-        l3.f_prop(&vec![test28.clone(), test28.clone(), test28.clone()])
-    ); */
-
-    match l1_out {
-        LayerOutput::Conv(out) => {
-            l1.b_prop(&out.into_iter().flatten().collect(), 0.1);
-        }
-        LayerOutput::Dense(_) => todo!(),
-        LayerOutput::None => todo!(),
+    let mut l1 = ConvolutionLayer::new((1, 8, 8), (1, 3)).unwrap();
+    let test = Tensor::from_fn(&[2, 1, 8, 8], |i| (i % 64) as f32);
+    let l1_out = l1.f_prop(&test);
+    assert_eq!(l1_out.tensor().shape(), &[2, 1, 6, 6]);
+
+    let mut l2 = ConvolutionLayer::new((2, 28, 28), (2, 5)).unwrap();
+    let test28 = Tensor::from_fn(&[1, 2, 28, 28], |i| (i % (28 * 28)) as f32);
+    let l2_out = l2.f_prop(&test28);
+    assert_eq!(l2_out.tensor().shape(), &[1, 2, 24, 24]);
+
+    let l1_out = l1_out.into_tensor();
+    let grad = l1.b_prop(&l1_out.reshape(&[2, 36]));
+    assert_eq!(grad.shape(), &[2, 1, 8, 8]);
+
+    // the index table is not saved, a loaded layer rebuilds it
+    let loaded: ConvolutionLayer =
+        serde_cbor::from_slice(&serde_cbor::to_vec(&l2).unwrap()).unwrap();
+    assert!(loaded.table.0.get().is_none());
+    assert_eq!(
+        loaded.f_prop_ref(&test28).tensor().as_slice(),
+        l2.f_prop_ref(&test28).tensor().as_slice()
+    );
+}
+
+#[test]
+fn conv_stride_padding_dilation() {
+    let options = |stride, padding, padding_mode, dilation| ConvOptions {
+        stride,
+        padding,
+        padding_mode,
+        dilation,
+    };
+    let shape = |l: &ConvolutionLayer| l.output_shape;
+
+    let same = ConvolutionLayer::with_options(
+        (1, 7, 7),
+        (1, 3),
+        options(1, Padding::Same, PaddingMode::Zeros, 1),
+    )
+    .unwrap();
+    assert_eq!(shape(&same), (1, 7, 7));
+    let strided = ConvolutionLayer::with_options(
+        (1, 7, 7),
+        (1, 3),
+        options(2, Padding::Same, PaddingMode::Zeros, 1),
+    )
+    .unwrap();
+    assert_eq!(shape(&strided), (1, 4, 4));
+    let dilated = ConvolutionLayer::with_options(
+        (1, 7, 7),
+        (1, 3),
+        options(1, Padding::Valid, PaddingMode::Zeros, 2),
+    )
+    .unwrap();
+    assert_eq!(shape(&dilated), (1, 3, 3));
+    assert!(matches!(
+        ConvolutionLayer::new((1, 2, 2), (1, 3)),
+        Err(Error::InvalidConfig(_))
+    ));
+
+    // every mode is linear in the input, so backprop has to be the exact adjoint of f_prop:
+    // <f_prop(x), g> == <x, b_prop(g)>
+    for mode in [
+        PaddingMode::Zeros,
+        PaddingMode::Reflect,
+        PaddingMode::Replicate,
+    ] {
+        let mut l = ConvolutionLayer::with_options(
+            (1, 6, 5),
+            (1, 3),
+            options(2, Padding::Explicit(2), mode, 2),
+        )
+        .unwrap();
+        l.biases.fill(0f32);
+        let x = Tensor::from_fn(&[1, 1, 6, 5], |i| (i as f32 * 0.7).cos());
+        let y = l.f_prop(&x).into_tensor();
+        let g = Tensor::from_fn(y.shape(), |i| (i as f32 * 0.3).sin());
+        let dx = l.b_prop(&g);
+        assert!((y.dot(&g) - x.dot(&dx)).abs() < 1e-4, "{:?}", mode);
     }
+}
 
-    /* match l2_out {
-        LayerOutput::Conv(out) => {
-            l2.b_prop(&out.into_iter().flatten().collect(), 0.1);
-        }
-        LayerOutput::Dense(_) => todo!(),
-        LayerOutput::None => todo!(),
-    } */
+#[test]
+fn conv_multi_channel_gradient_check() {
+    // E = <f_prop(x), g>, so dE/dY = g and every gradient can be checked by finite differences
+    let options = ConvOptions {
+        stride: 2,
+        padding: Padding::Same,
+        ..ConvOptions::default()
+    };
+    let mut l = ConvolutionLayer::with_options((2, 5, 5), (3, 3), options).unwrap();
+    assert_eq!(l.kernels.shape(), &[3, 2, 3, 3]);
+    assert_eq!(l.biases.shape(), &[3]);
+    let x = Tensor::from_fn(&[2, 2, 5, 5], |i| (i as f32 * 0.37).sin());
+    let g = Tensor::from_fn(&[2, 3, 3, 3], |i| (i as f32 * 0.11).cos());
+    let error = |l: &ConvolutionLayer, x: &Tensor| l.f_prop_ref(x).tensor().dot(&g);
+
+    l.f_prop(&x);
+    let dx = l.b_prop(&g);
+    let h = 1e-2;
+    let check = |analytic: f32, plus: f32, minus: f32| {
+        let numeric = (plus - minus) / (2f32 * h);
+        assert!(
+            (analytic - numeric).abs() < 1e-2,
+            "{} vs {}",
+            analytic,
+            numeric
+        );
+    };
+    for i in 0..l.kernels.len() {
+        let mut p = l.clone();
+        p.kernels.as_mut_slice()[i] += h;
+        let mut m = l.clone();
+        m.kernels.as_mut_slice()[i] -= h;
+        check(l.kernel_grad.as_slice()[i], error(&p, &x), error(&m, &x));
+    }
+    for i in 0..l.biases.len() {
+        let mut p = l.clone();
+        p.biases.as_mut_slice()[i] += h;
+        let mut m = l.clone();
+        m.biases.as_mut_slice()[i] -= h;
+        check(l.bias_grad.as_slice()[i], error(&p, &x), error(&m, &x));
+    }
+    for i in 0..x.len() {
+        let mut p = x.clone();
+        p.as_mut_slice()[i] += h;
+        let mut m = x.clone();
+        m.as_mut_slice()[i] -= h;
+        check(dx.as_slice()[i], error(&l, &p), error(&l, &m));
+    }
 }
@@ -1,131 +1,249 @@
-use super::LayerOutput;
-use rand::thread_rng;
+use super::{LayerOutput, Param};
+use crate::error::Result;
+use crate::gemm::{self, Matrix};
+use crate::init::Initializer;
+use crate::rng;
+use crate::tensor::Tensor;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct DenseLayer {
-    pub input: Vec<f32>,
-    pub weights: Vec<Vec<f32>>, //vec[nth neuron][weights]
-    pub biases: Vec<f32>,
+    #[serde(skip)]
+    pub input: Tensor,
+    pub weights: Tensor, // [output_size, input_size], one row per neuron
+    pub biases: Tensor,  // [output_size]
+    #[serde(skip)]
+    pub weight_grad: Tensor,
+    #[serde(skip)]
+    pub bias_grad: Tensor,
 }
 
 impl DenseLayer {
+    // Xavier uniform weights and zero biases, see `with_init`
     pub fn new(input_size: usize, output_size: usize) -> Self {
-        let mut weights = Vec::with_capacity(input_size * output_size);
-        let mut biases = Vec::with_capacity(output_size);
-        let mut rng = thread_rng();
-        let mut temp: Vec<f32> = vec![0f32; input_size];
-        for _y in 0..output_size {
-            for _x in 0..input_size {
-                temp[_x] = rng.gen_range(-1f32..1f32);
-            }
-            weights.push(temp.clone());
-        }
-        for _y in 0..output_size {
-            biases.push(rng.gen_range(-1f32..1f32));
-        }
-        thread_rng().try_fill(&mut biases[..]).unwrap();
+        Self::drawn(
+            input_size,
+            output_size,
+            Initializer::default(),
+            &mut rng::rng(),
+        )
+    }
+
+    // fails when `init` does, see `Initializer::check`
+    pub fn with_init(input_size: usize, output_size: usize, init: Initializer) -> Result<Self> {
+        Self::with_rng(input_size, output_size, init, &mut rng::rng())
+    }
+
+    pub fn with_rng<R: Rng + ?Sized>(
+        input_size: usize,
+        output_size: usize,
+        init: Initializer,
+        rng: &mut R,
+    ) -> Result<Self> {
+        init.check()?;
+        Ok(Self::drawn(input_size, output_size, init, rng))
+    }
+
+    fn drawn<R: Rng + ?Sized>(
+        input_size: usize,
+        output_size: usize,
+        init: Initializer,
+        rng: &mut R,
+    ) -> Self {
+        let weights = init.draw(&[output_size, input_size], rng);
+        let biases = Tensor::zeros(&[output_size]);
 
         Self {
-            input: vec![0f32; input_size],
+            input: Tensor::zeros(&[1, input_size]),
+            weight_grad: Tensor::zeros(weights.shape()),
+            bias_grad: Tensor::zeros(biases.shape()),
             weights,
             biases,
         }
     }
 
-    pub fn f_prop(&mut self, input: &Vec<f32>) -> LayerOutput {
-        assert_eq!(self.input.len(), input.len());
-
-        //println!("{} {}", self.biases.len(), self.weights[0].len());
-        self.input = input.to_vec();
-        LayerOutput::Dense(
-            self.weights
-                .iter()
-                //y.j = x.i * w.j.i
-                .map(|neuron| {
-                    neuron
-                        .iter()
-                        .zip(input.iter())
-                        .fold(0f32, |p, (w, i)| p + w * i)
-                })
-                //y.j += b.j
-                .zip(self.biases.iter())
-                .map(|(o, b)| o + b)
-                .collect(),
-        )
+    // Draws new weights and zeroes the biases.
+    pub fn initialize<R: Rng + ?Sized>(&mut self, init: Initializer, rng: &mut R) -> Result<()> {
+        self.weights = init.tensor(self.weights.shape(), rng)?;
+        self.biases.fill(0f32);
+        Ok(())
     }
 
-    pub fn f_prop_ref(&self, input: &Vec<f32>) -> LayerOutput {
-        LayerOutput::Dense(
-            self.weights
-                .iter()
-                //y.j = x.i * w.j.i
-                .map(|neuron| {
-                    neuron
-                        .iter()
-                        .zip(input.iter())
-                        .fold(0f32, |p, (w, i)| p + w * i)
-                })
-                //y.j += b.j
-                .zip(self.biases.iter())
-                .map(|(o, b)| o + b)
-                .collect(),
-        )
+    pub fn f_prop(&mut self, input: &Tensor) -> LayerOutput {
+        let mut y = Tensor::default();
+        self.f_prop_into(input, &mut y);
+        LayerOutput::Dense(y)
     }
 
-    pub fn b_prop(&mut self, output_gradient: &Vec<f32>, learning_rate: f32) -> Vec<f32> {
-        // dot product of output_gradient vec[_] * vec[input]
-        let mut weight_grad: Vec<Vec<f32>> =
-            Vec::with_capacity(output_gradient.len() * self.input.len());
-        let mut temp: Vec<f32> = vec![0f32; self.input.len()];
-        for r in output_gradient.iter() {
-            for (i, c) in self.input.iter().enumerate() {
-                temp[i] = c * r;
-            }
-            weight_grad.push(temp.clone());
+    // `f_prop` into `output`. Nothing is allocated once the buffers have the batch's shapes.
+    pub fn f_prop_into(&mut self, input: &Tensor, output: &mut Tensor) {
+        if self.input.shape() == input.shape() {
+            self.input.as_mut_slice().copy_from_slice(input.as_slice());
+        } else {
+            self.input = input.clone();
+        }
+        self.forward(input, output);
+    }
+
+    // input: [batch, input_size] -> [batch, output_size]
+    pub fn f_prop_ref(&self, input: &Tensor) -> LayerOutput {
+        let mut y = Tensor::default();
+        self.forward(input, &mut y);
+        LayerOutput::Dense(y)
+    }
+
+    fn forward(&self, input: &Tensor, y: &mut Tensor) {
+        assert_eq!(input.shape()[1], self.weights.shape()[1]);
+        //y.b.j = x.b.i * w.j.i + b.j
+        let batch = input.shape()[0];
+        reshape(y, &[batch, self.biases.len()]);
+        for row in 0..batch {
+            y.row_mut(row).copy_from_slice(self.biases.as_slice());
         }
+        input.view().matmul_into(&self.weights.t(), y);
+    }
+
+    pub(crate) fn infer(&self, input: &[f32], output: &mut [f32]) {
+        let (output_size, input_size) = (self.weights.shape()[0], self.weights.shape()[1]);
+        output.copy_from_slice(self.biases.as_slice());
+        // x [1, input_size] . w^T [input_size, output_size]
+        let x = Matrix {
+            data: input,
+            rows: 1,
+            cols: input_size,
+            row_stride: input_size,
+            col_stride: 1,
+        };
+        let w_t = Matrix {
+            data: self.weights.as_slice(),
+            rows: input_size,
+            cols: output_size,
+            row_stride: 1,
+            col_stride: input_size,
+        };
+        gemm::gemm(x, w_t, output);
+    }
 
-        let mut weight_t: Vec<Vec<f32>> =
-            Vec::with_capacity(self.weights[0].len() * self.weights.len());
-        let mut temp: Vec<f32> = vec![0f32; self.weights.len()];
-        for col in 0..self.weights[0].len() {
-            for (i, row) in self.weights.iter().enumerate() {
-                temp[i] = row[col];
+    // output_gradient: [batch, output_size]. The gradients of every sample in the batch are
+    // summed into weight_grad / bias_grad, the weights themselves are left untouched.
+    pub fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        let mut input_gradient = Tensor::default();
+        self.b_prop_into(output_gradient, &mut input_gradient);
+        input_gradient
+    }
+
+    // `b_prop` into `input_gradient`, which is [batch, input_size] afterwards. Nothing is
+    // allocated once it has that shape.
+    pub fn b_prop_into(&mut self, output_gradient: &Tensor, input_gradient: &mut Tensor) {
+        if self.weight_grad.shape() != self.weights.shape()
+            || self.bias_grad.shape() != self.biases.shape()
+        {
+            self.zero_grad();
+        }
+        // output_gradient.t [neurons, batch] * input [batch, weights], straight into the
+        // accumulated gradient
+        output_gradient
+            .t()
+            .matmul_into(&self.input.view(), &mut self.weight_grad);
+        for row in 0..output_gradient.shape()[0] {
+            for (b, g) in self
+                .bias_grad
+                .as_mut_slice()
+                .iter_mut()
+                .zip(output_gradient.row(row))
+            {
+                *b += g;
             }
-            weight_t.push(temp.clone());
         }
 
-        // dot product of vec[neurons][weights].t * vec[out_grad]
-        let input_grad: Vec<f32> = weight_t
-            .iter()
-            .map(|row| {
-                row.iter()
-                    .zip(output_gradient.iter())
-                    .fold(0f32, |p, (w, og)| p + w * og)
-            })
-            .collect();
-
-        self.weights = self
-            .weights
-            .iter()
-            .zip(weight_grad.iter())
-            .map(|(neuron, nwg)| {
-                neuron
-                    .iter()
-                    .zip(nwg.iter())
-                    .map(|(w, wg)| w - wg * learning_rate)
-                    .collect()
-            })
-            .collect();
-
-        self.biases = self
-            .biases
-            .iter()
-            .zip(output_gradient)
-            .map(|(b, og)| b - og * learning_rate)
-            .collect();
-
-        input_grad
+        // output_gradient [batch, neurons] * weights [neurons, weights]
+        reshape(
+            input_gradient,
+            &[output_gradient.shape()[0], self.weights.shape()[1]],
+        );
+        input_gradient.fill(0f32);
+        output_gradient
+            .view()
+            .matmul_into(&self.weights.view(), input_gradient);
+    }
+
+    pub fn params(&mut self) -> Vec<Param<'_>> {
+        vec![
+            Param::new(&mut self.weights, &mut self.weight_grad),
+            Param::new(&mut self.biases, &mut self.bias_grad),
+        ]
+    }
+
+    pub fn zero_grad(&mut self) {
+        for (grad, shape) in [
+            (&mut self.weight_grad, self.weights.shape()),
+            (&mut self.bias_grad, self.biases.shape()),
+        ] {
+            reshape(grad, shape);
+            grad.fill(0f32);
+        }
+    }
+}
+
+// `tensor` with `shape`, reallocated only when it had another one. The values are left as they
+// are when the shape is unchanged.
+fn reshape(tensor: &mut Tensor, shape: &[usize]) {
+    if tensor.shape() != shape {
+        *tensor = Tensor::zeros(shape);
     }
 }
+
+#[test]
+fn dense_batch_sums_sample_gradients() {
+    let layer = DenseLayer::new(3, 2);
+    let x = Tensor::new(vec![1f32, 2f32, 3f32, -1f32, 0.5f32, 2f32], &[2, 3]);
+    let og = Tensor::new(vec![0.1f32, -0.2f32, 0.3f32, 0.4f32], &[2, 2]);
+
+    let mut batched = layer.clone();
+    batched.f_prop(&x);
+    batched.b_prop(&og);
+
+    // accumulating one sample at a time gives the same gradient
+    let mut single = layer.clone();
+    for sample in 0..2 {
+        single.f_prop(&Tensor::new(x.row(sample).to_vec(), &[1, 3]));
+        single.b_prop(&Tensor::new(og.row(sample).to_vec(), &[1, 2]));
+    }
+    let pairs = [
+        (&batched.weight_grad, &single.weight_grad),
+        (&batched.bias_grad, &single.bias_grad),
+    ];
+    for (summed, accumulated) in pairs {
+        assert!(summed.as_slice().iter().any(|g| *g != 0f32));
+        for (a, b) in summed.as_slice().iter().zip(accumulated.as_slice()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn dense_reuses_its_buffers() {
+    let mut layer = DenseLayer::new(3, 2);
+    let x = Tensor::new(vec![1f32, 2f32, 3f32, -1f32, 0.5f32, 2f32], &[2, 3]);
+    let og = Tensor::new(vec![0.1f32, -0.2f32, 0.3f32, 0.4f32], &[2, 2]);
+    let (mut y, mut ig) = (Tensor::zeros(&[2, 2]), Tensor::zeros(&[2, 3]));
+    layer.f_prop_into(&x, &mut y);
+    layer.b_prop_into(&og, &mut ig);
+    let buffers = |layer: &DenseLayer, y: &Tensor, ig: &Tensor| {
+        [
+            layer.input.as_slice().as_ptr(),
+            layer.weight_grad.as_slice().as_ptr(),
+            y.as_slice().as_ptr(),
+            ig.as_slice().as_ptr(),
+        ]
+    };
+    let before = buffers(&layer, &y, &ig);
+
+    layer.zero_grad();
+    layer.f_prop_into(&x, &mut y);
+    layer.b_prop_into(&og, &mut ig);
+    assert_eq!(buffers(&layer, &y, &ig), before);
+    assert_eq!(y.as_slice(), layer.f_prop(&x).tensor().as_slice());
+    assert_eq!(ig.as_slice(), layer.b_prop(&og).as_slice());
+}
@@ -1,19 +1,165 @@
+use crate::error::Result;
+use crate::init::Initializer;
+use crate::tensor::Tensor;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 pub mod convolution;
 pub mod dense;
+pub mod pooling;
 
 // Forward prop output
-#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum LayerOutput {
-    Conv(Vec<Vec<f32>>),
-    Dense(Vec<f32>),
-    #[default]
-    None,
+    Conv(Tensor),  // [batch, depth, height, width]
+    Dense(Tensor), // [batch, size]
+}
+
+// an empty batch, what modules hold before their first forward pass
+impl Default for LayerOutput {
+    fn default() -> Self {
+        LayerOutput::Dense(Tensor::zeros(&[0, 0]))
+    }
+}
+
+impl LayerOutput {
+    pub fn tensor(&self) -> &Tensor {
+        match self {
+            LayerOutput::Conv(t) | LayerOutput::Dense(t) => t,
+        }
+    }
+
+    pub fn into_tensor(self) -> Tensor {
+        match self {
+            LayerOutput::Conv(t) | LayerOutput::Dense(t) => t,
+        }
+    }
+}
+
+// A trainable tensor and the gradient back propagation accumulated for it since the last
+// `zero_grad`.
+pub struct Param<'a> {
+    pub value: &'a mut Tensor,
+    pub grad: &'a mut Tensor,
+}
+
+impl<'a> Param<'a> {
+    // gradients are not serialized, so they are (re)allocated on first use
+    pub(crate) fn new(value: &'a mut Tensor, grad: &'a mut Tensor) -> Self {
+        if grad.shape() != value.shape() {
+            *grad = Tensor::zeros(value.shape());
+        }
+        Param { value, grad }
+    }
+}
+
+pub(crate) fn accumulate(grad: &mut Tensor, delta: &Tensor) {
+    if grad.shape() != delta.shape() {
+        *grad = delta.clone();
+    } else {
+        *grad += delta;
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum LayerType {
     Dense(dense::DenseLayer),
     Conv(convolution::ConvolutionLayer),
+    MaxPool2D(pooling::MaxPool2D),
+    AvgPool2D(pooling::AvgPool2D),
+    GlobalAvgPool(pooling::GlobalAvgPool),
+}
+
+impl LayerType {
+    pub fn f_prop(&mut self, input: LayerOutput) -> LayerOutput {
+        match self {
+            // currenty just flattening the tensor. not sure if this is the proper way to do it.
+            LayerType::Dense(layer) => layer.f_prop(&flatten(input)),
+            LayerType::Conv(layer) => layer.f_prop(&unflatten(input, layer.input_shape)),
+            LayerType::MaxPool2D(layer) => layer.f_prop(&unflatten(input, layer.input_shape())),
+            LayerType::AvgPool2D(layer) => layer.f_prop(&unflatten(input, layer.input_shape())),
+            LayerType::GlobalAvgPool(layer) => layer.f_prop(&unflatten(input, layer.input_shape)),
+        }
+    }
+
+    pub fn f_prop_ref(&self, input: LayerOutput) -> LayerOutput {
+        match self {
+            LayerType::Dense(layer) => layer.f_prop_ref(&flatten(input)),
+            LayerType::Conv(layer) => layer.f_prop_ref(&unflatten(input, layer.input_shape)),
+            LayerType::MaxPool2D(layer) => layer.f_prop_ref(&unflatten(input, layer.input_shape())),
+            LayerType::AvgPool2D(layer) => layer.f_prop_ref(&unflatten(input, layer.input_shape())),
+            LayerType::GlobalAvgPool(layer) => {
+                layer.f_prop_ref(&unflatten(input, layer.input_shape))
+            }
+        }
+    }
+
+    // One sample for inference, from and into plain slices, so the `infer` of every layer type
+    // can run on a `Workspace` without allocating. `cols` is scratch space for convolutions, see
+    // `ConvolutionLayer::cols_len`.
+    pub(crate) fn infer(&self, input: &[f32], output: &mut [f32], cols: &mut [f32]) {
+        match self {
+            LayerType::Dense(layer) => layer.infer(input, output),
+            LayerType::Conv(layer) => layer.infer(input, output, cols),
+            LayerType::MaxPool2D(layer) => layer.infer(input, output),
+            LayerType::AvgPool2D(layer) => layer.infer(input, output),
+            LayerType::GlobalAvgPool(layer) => layer.infer(input, output),
+        }
+    }
+
+    pub fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        match self {
+            LayerType::Dense(layer) => layer.b_prop(output_gradient),
+            LayerType::Conv(layer) => layer.b_prop(output_gradient),
+            LayerType::MaxPool2D(layer) => layer.b_prop(output_gradient),
+            LayerType::AvgPool2D(layer) => layer.b_prop(output_gradient),
+            LayerType::GlobalAvgPool(layer) => layer.b_prop(output_gradient),
+        }
+    }
+
+    pub fn params(&mut self) -> Vec<Param<'_>> {
+        match self {
+            LayerType::Dense(layer) => layer.params(),
+            LayerType::Conv(layer) => layer.params(),
+            LayerType::MaxPool2D(_) | LayerType::AvgPool2D(_) | LayerType::GlobalAvgPool(_) => {
+                vec![]
+            }
+        }
+    }
+
+    // Redraws the weights of dense and convolution layers, the others have none. Returns whether
+    // there was anything to initialize.
+    pub fn initialize<R: Rng + ?Sized>(&mut self, init: Initializer, rng: &mut R) -> Result<bool> {
+        match self {
+            LayerType::Dense(layer) => layer.initialize(init, rng)?,
+            LayerType::Conv(layer) => layer.initialize(init, rng)?,
+            LayerType::MaxPool2D(_) | LayerType::AvgPool2D(_) | LayerType::GlobalAvgPool(_) => {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn zero_grad(&mut self) {
+        match self {
+            LayerType::Dense(layer) => layer.zero_grad(),
+            LayerType::Conv(layer) => layer.zero_grad(),
+            LayerType::MaxPool2D(_) | LayerType::AvgPool2D(_) | LayerType::GlobalAvgPool(_) => {}
+        }
+    }
+}
+
+// [batch, ..] -> [batch, size]
+fn flatten(output: LayerOutput) -> Tensor {
+    let t = output.into_tensor();
+    let batch = t.shape()[0];
+    let len = t.len();
+    t.reshape(&[batch, len / batch])
+}
+
+// [batch, ..] -> [batch, depth, height, width]
+fn unflatten(output: LayerOutput, (depth, height, width): (usize, usize, usize)) -> Tensor {
+    let t = output.into_tensor();
+    let batch = t.shape()[0];
+    t.reshape(&[batch, depth, height, width])
 }
@@ -0,0 +1,360 @@
+use super::LayerOutput;
+use crate::error::{Error, Result};
+use crate::tensor::Tensor;
+use serde::{Deserialize, Serialize};
+
+// Geometry shared by the windowed pooling layers.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+struct Window {
+    input_shape: (usize, usize, usize), // (depth, height, width)
+    output_shape: (usize, usize, usize),
+    size: usize,
+    stride: usize,
+    padding: usize,
+}
+
+impl Window {
+    fn new(
+        input_shape: (usize, usize, usize),
+        size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> std::result::Result<Self, String> {
+        let (depth, height, width) = input_shape;
+        if size == 0 || stride == 0 {
+            return Err("pooling size and stride must be positive".into());
+        }
+        if padding * 2 > size || height + 2 * padding < size || width + 2 * padding < size {
+            return Err(format!(
+                "pooling window {} with padding {} does not fit {:?}",
+                size, padding, input_shape
+            ));
+        }
+        let output_shape = (
+            depth,
+            (height + 2 * padding - size) / stride + 1,
+            (width + 2 * padding - size) / stride + 1,
+        );
+        Ok(Self {
+            input_shape,
+            output_shape,
+            size,
+            stride,
+            padding,
+        })
+    }
+
+    // Calls `f(output_index, input_indices)` for every output of one sample. Input indices are
+    // relative to the sample and only cover the part of the window inside the (unpadded) input.
+    fn for_each(&self, mut f: impl FnMut(usize, &mut dyn Iterator<Item = usize>)) {
+        let (depth, height, width) = self.input_shape;
+        let (_, out_height, out_width) = self.output_shape;
+        for d in 0..depth {
+            for oy in 0..out_height {
+                for ox in 0..out_width {
+                    let y0 = (oy * self.stride) as isize - self.padding as isize;
+                    let x0 = (ox * self.stride) as isize - self.padding as isize;
+                    let mut window = (0..self.size * self.size).filter_map(|k| {
+                        let y = y0 + (k / self.size) as isize;
+                        let x = x0 + (k % self.size) as isize;
+                        if y < 0 || x < 0 || y >= height as isize || x >= width as isize {
+                            return None;
+                        }
+                        Some((d * height + y as usize) * width + x as usize)
+                    });
+                    f((d * out_height + oy) * out_width + ox, &mut window);
+                }
+            }
+        }
+    }
+
+    fn output_tensor(&self, batch: usize) -> Tensor {
+        let (depth, height, width) = self.output_shape;
+        Tensor::zeros(&[batch, depth, height, width])
+    }
+
+    fn input_tensor(&self, batch: usize) -> Tensor {
+        let (depth, height, width) = self.input_shape;
+        Tensor::zeros(&[batch, depth, height, width])
+    }
+}
+
+// Takes the maximum of every window. Padding never wins the max.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct MaxPool2D {
+    window: Window,
+    // for every output of the last f_prop, the flat input index that held the maximum
+    #[serde(skip)]
+    argmax: Vec<usize>,
+}
+
+impl MaxPool2D {
+    // fails when the window does not fit the input
+    pub fn new(
+        input_shape: (usize, usize, usize),
+        size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> Result<Self> {
+        Self::try_new(input_shape, size, stride, padding).map_err(Error::InvalidConfig)
+    }
+
+    pub(crate) fn try_new(
+        input_shape: (usize, usize, usize),
+        size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> std::result::Result<Self, String> {
+        Ok(Self {
+            window: Window::new(input_shape, size, stride, padding)?,
+            argmax: Vec::new(),
+        })
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize) {
+        self.window.input_shape
+    }
+
+    pub fn output_shape(&self) -> (usize, usize, usize) {
+        self.window.output_shape
+    }
+
+    pub fn f_prop(&mut self, input: &Tensor) -> LayerOutput {
+        let mut argmax = Vec::new();
+        let out = self.pool(input, Some(&mut argmax));
+        self.argmax = argmax;
+        out
+    }
+
+    pub fn f_prop_ref(&self, input: &Tensor) -> LayerOutput {
+        self.pool(input, None)
+    }
+
+    pub(crate) fn infer(&self, input: &[f32], output: &mut [f32]) {
+        self.pool_sample(input, output, |_| {});
+    }
+
+    fn pool(&self, input: &Tensor, mut argmax: Option<&mut Vec<usize>>) -> LayerOutput {
+        let batch = input.shape()[0];
+        let mut out = self.window.output_tensor(batch);
+        for sample in 0..batch {
+            let x = input.row(sample);
+            let offset = sample * x.len();
+            self.pool_sample(x, out.row_mut(sample), |i| {
+                if let Some(argmax) = argmax.as_deref_mut() {
+                    argmax.push(offset + i);
+                }
+            });
+        }
+        LayerOutput::Conv(out)
+    }
+
+    // `on_max` gets the input index of every maximum, in output order
+    fn pool_sample(&self, x: &[f32], out: &mut [f32], mut on_max: impl FnMut(usize)) {
+        self.window.for_each(|o, window| {
+            let (i, max) =
+                window
+                    .map(|i| (i, x[i]))
+                    .fold((usize::MAX, f32::NEG_INFINITY), |best, (i, v)| {
+                        if v > best.1 || best.0 == usize::MAX {
+                            (i, v)
+                        } else {
+                            best
+                        }
+                    });
+            out[o] = max;
+            on_max(i);
+        });
+    }
+
+    // the gradient of each output goes to the input that was the max of its window
+    pub fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        let batch = output_gradient.shape()[0];
+        let mut input_grad = self.window.input_tensor(batch);
+        let grad = input_grad.as_mut_slice();
+        for (i, g) in self.argmax.iter().zip(output_gradient.as_slice()) {
+            grad[*i] += g;
+        }
+        input_grad
+    }
+}
+
+// Averages every window. Padding counts as zeros towards the average.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct AvgPool2D {
+    window: Window,
+}
+
+impl AvgPool2D {
+    // fails when the window does not fit the input
+    pub fn new(
+        input_shape: (usize, usize, usize),
+        size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> Result<Self> {
+        Self::try_new(input_shape, size, stride, padding).map_err(Error::InvalidConfig)
+    }
+
+    pub(crate) fn try_new(
+        input_shape: (usize, usize, usize),
+        size: usize,
+        stride: usize,
+        padding: usize,
+    ) -> std::result::Result<Self, String> {
+        Ok(Self {
+            window: Window::new(input_shape, size, stride, padding)?,
+        })
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize) {
+        self.window.input_shape
+    }
+
+    pub fn output_shape(&self) -> (usize, usize, usize) {
+        self.window.output_shape
+    }
+
+    pub fn f_prop(&mut self, input: &Tensor) -> LayerOutput {
+        self.f_prop_ref(input)
+    }
+
+    pub fn f_prop_ref(&self, input: &Tensor) -> LayerOutput {
+        let batch = input.shape()[0];
+        let mut out = self.window.output_tensor(batch);
+        for sample in 0..batch {
+            self.infer(input.row(sample), out.row_mut(sample));
+        }
+        LayerOutput::Conv(out)
+    }
+
+    pub(crate) fn infer(&self, input: &[f32], output: &mut [f32]) {
+        let area = (self.window.size * self.window.size) as f32;
+        self.window.for_each(|o, window| {
+            output[o] = window.map(|i| input[i]).sum::<f32>() / area;
+        });
+    }
+
+    pub fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        let batch = output_gradient.shape()[0];
+        let area = (self.window.size * self.window.size) as f32;
+        let mut input_grad = self.window.input_tensor(batch);
+        for sample in 0..batch {
+            let og = output_gradient.row(sample);
+            let grad = input_grad.row_mut(sample);
+            self.window.for_each(|o, window| {
+                window.for_each(|i| grad[i] += og[o] / area);
+            });
+        }
+        input_grad
+    }
+}
+
+// Averages every channel down to a single value: [batch, depth, height, width] -> [batch, depth].
+// Lets conv features feed a dense head without flattening.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct GlobalAvgPool {
+    pub(crate) input_shape: (usize, usize, usize),
+}
+
+impl GlobalAvgPool {
+    // fails on an empty input, there would be nothing to average
+    pub fn new(input_shape: (usize, usize, usize)) -> Result<Self> {
+        Self::try_new(input_shape).map_err(Error::InvalidConfig)
+    }
+
+    pub(crate) fn try_new(input_shape: (usize, usize, usize)) -> std::result::Result<Self, String> {
+        let (depth, height, width) = input_shape;
+        if depth == 0 || height == 0 || width == 0 {
+            return Err(format!(
+                "global average pooling needs a non empty input, got {:?}",
+                input_shape
+            ));
+        }
+        Ok(Self { input_shape })
+    }
+
+    pub fn f_prop(&mut self, input: &Tensor) -> LayerOutput {
+        self.f_prop_ref(input)
+    }
+
+    pub fn f_prop_ref(&self, input: &Tensor) -> LayerOutput {
+        let batch = input.shape()[0];
+        let mut out = Tensor::zeros(&[batch, self.input_shape.0]);
+        for sample in 0..batch {
+            self.infer(input.row(sample), out.row_mut(sample));
+        }
+        LayerOutput::Dense(out)
+    }
+
+    pub(crate) fn infer(&self, input: &[f32], output: &mut [f32]) {
+        let (_, height, width) = self.input_shape;
+        let plane = height * width;
+        for (o, channel) in output.iter_mut().zip(input.chunks(plane)) {
+            *o = channel.iter().sum::<f32>() / plane as f32;
+        }
+    }
+
+    pub fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        let batch = output_gradient.shape()[0];
+        let (depth, height, width) = self.input_shape;
+        let plane = height * width;
+        let mut input_grad = Tensor::zeros(&[batch, depth, height, width]);
+        for sample in 0..batch {
+            let og = output_gradient.row(sample);
+            for (channel, g) in input_grad.row_mut(sample).chunks_mut(plane).zip(og) {
+                channel.iter_mut().for_each(|x| *x = g / plane as f32);
+            }
+        }
+        input_grad
+    }
+}
+
+#[test]
+fn pooling_f_b_prop() {
+    let input = Tensor::new(
+        vec![
+            1f32, 5f32, 2f32, 0f32, //
+            3f32, 4f32, 8f32, 1f32, //
+            0f32, 2f32, 7f32, 6f32, //
+            9f32, 1f32, 3f32, 2f32, //
+        ],
+        &[1, 1, 4, 4],
+    );
+
+    let mut max = MaxPool2D::new((1, 4, 4), 2, 2, 0).unwrap();
+    let out = max.f_prop(&input);
+    assert_eq!(out.tensor().as_slice(), &[5f32, 8f32, 9f32, 7f32]);
+    let grad = max.b_prop(&Tensor::new(vec![1f32, 2f32, 3f32, 4f32], &[1, 1, 2, 2]));
+    let mut expected = [0f32; 16];
+    expected[1] = 1f32;
+    expected[6] = 2f32;
+    expected[12] = 3f32;
+    expected[10] = 4f32;
+    assert_eq!(grad.as_slice(), &expected[..]);
+
+    // padded, overlapping windows: 3x3 stride 1 padding 1 keeps the spatial size
+    let mut avg = AvgPool2D::new((1, 4, 4), 3, 1, 1).unwrap();
+    assert!(matches!(
+        MaxPool2D::new((1, 4, 4), 5, 1, 0),
+        Err(Error::InvalidConfig(_))
+    ));
+    let out = avg.f_prop(&input);
+    assert_eq!(out.tensor().shape(), &[1, 1, 4, 4]);
+    assert!((out.tensor().get(&[0, 0, 0, 0]) - 13f32 / 9f32).abs() < 1e-6);
+    // the gradient is the transpose of the forward: <avg(x), g> == <x, b_prop(g)>
+    let g = Tensor::from_fn(&[1, 1, 4, 4], |i| (i as f32 * 0.37).sin());
+    let grad = avg.b_prop(&g);
+    assert!((out.tensor().dot(&g) - input.dot(&grad)).abs() < 1e-4);
+
+    let mut global = GlobalAvgPool::new((1, 4, 4)).unwrap();
+    assert_eq!(global.f_prop(&input).tensor().as_slice(), &[3.375f32]);
+    assert_eq!(
+        global.b_prop(&Tensor::new(vec![16f32], &[1, 1])).as_slice(),
+        &[1f32; 16]
+    );
+    assert!(matches!(
+        GlobalAvgPool::new((1, 0, 4)),
+        Err(Error::InvalidConfig(_))
+    ));
+}
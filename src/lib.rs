@@ -1,5 +1,17 @@
 pub mod activations;
+pub mod builder;
+pub mod callbacks;
+pub mod checkpoint;
+pub mod data;
+pub mod error;
+mod gemm;
+pub mod init;
 pub mod layer;
 pub mod loss;
+pub mod metrics;
 pub mod network;
+pub mod optim;
+pub mod rng;
+pub mod tensor;
 pub mod trainer;
+pub mod workspace;
@@ -1,14 +1,28 @@
+use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
 
 pub trait Loss {
-    fn loss(&self, truth: &Vec<f32>, prediction: &Vec<f32>) -> f32;
-    fn loss_prime(&self, truth: &Vec<f32>, prediction: &Vec<f32>) -> Vec<f32>;
+    fn loss(&self, truth: &[f32], prediction: &[f32]) -> f32;
+    fn loss_prime(&self, truth: &[f32], prediction: &[f32]) -> Vec<f32>;
+
+    // Whether the loss works on outputs of `size` values, checked by `Trainer` and `evaluate`
+    // before using it.
+    fn check(&self, _size: usize) -> Result<()> {
+        Ok(())
+    }
+
+    // Loss and gradient taken straight from the logits of a final softmax, for losses that have
+    // a cheaper and more stable combined form. `None` means the gradient has to go through the
+    // softmax Jacobian.
+    fn softmax_loss(&self, _truth: &[f32], _logits: &[f32]) -> Option<(f32, Vec<f32>)> {
+        None
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
 pub struct MSE;
 impl Loss for MSE {
-    fn loss(&self, truth: &Vec<f32>, prediction: &Vec<f32>) -> f32 {
+    fn loss(&self, truth: &[f32], prediction: &[f32]) -> f32 {
         let len = truth.len() as f32;
         truth
             .iter()
@@ -17,7 +31,7 @@ impl Loss for MSE {
             .sum::<f32>()
             / len
     }
-    fn loss_prime(&self, truth: &Vec<f32>, prediction: &Vec<f32>) -> Vec<f32> {
+    fn loss_prime(&self, truth: &[f32], prediction: &[f32]) -> Vec<f32> {
         let len = truth.len() as f32;
         truth
             .iter()
@@ -26,3 +40,594 @@ impl Loss for MSE {
             .collect()
     }
 }
+
+// keeps log(0) out of the probability based losses
+const EPSILON: f32 = 1e-7;
+
+// How the per-element losses of one sample are combined. `Mean` is the weighted mean, the sum
+// divided by the sum of the weights.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum Reduction {
+    #[default]
+    Mean,
+    Sum,
+    // not combined: the individual terms come from `ElementLoss::losses`. There is no single
+    // loss to train on, so `check` rejects it.
+    None,
+}
+
+// A loss made of one term per output element. Implementing this is enough to get the `Loss`
+// impl, with the per-class `weights` and the `reduction` applied on top.
+pub trait ElementLoss {
+    fn element(&self, truth: f32, prediction: f32) -> f32;
+    fn element_prime(&self, truth: f32, prediction: f32) -> f32;
+    fn weights(&self) -> Option<&[f32]>;
+    fn reduction(&self) -> Reduction;
+
+    fn losses(&self, truth: &[f32], prediction: &[f32]) -> Vec<f32> {
+        truth
+            .iter()
+            .zip(prediction.iter())
+            .enumerate()
+            .map(|(i, (t, p))| self.weight(i) * self.element(*t, *p))
+            .collect()
+    }
+
+    // `weights` has to have one weight per output, see `check`
+    fn weight(&self, i: usize) -> f32 {
+        self.weights().map_or(1f32, |w| w[i])
+    }
+
+    // what the summed terms are divided by
+    fn divisor(&self, size: usize) -> f32 {
+        match self.reduction() {
+            Reduction::Mean => self
+                .weights()
+                .map_or(size as f32, |w| w.iter().sum::<f32>()),
+            Reduction::Sum | Reduction::None => 1f32,
+        }
+    }
+}
+
+impl<T: ElementLoss> Loss for T {
+    // the sum of the terms with `Reduction::None`
+    fn loss(&self, truth: &[f32], prediction: &[f32]) -> f32 {
+        let sum: f32 = self.losses(truth, prediction).iter().sum();
+        sum / self.divisor(truth.len())
+    }
+
+    fn loss_prime(&self, truth: &[f32], prediction: &[f32]) -> Vec<f32> {
+        let scale = 1f32 / self.divisor(truth.len());
+        truth
+            .iter()
+            .zip(prediction.iter())
+            .enumerate()
+            .map(|(i, (t, p))| scale * self.weight(i) * self.element_prime(*t, *p))
+            .collect()
+    }
+
+    fn check(&self, size: usize) -> Result<()> {
+        if self.reduction() == Reduction::None {
+            return Err(Error::InvalidConfig(
+                "a loss with Reduction::None has no single value to train on".into(),
+            ));
+        }
+        let Some(weights) = self.weights() else {
+            return Ok(());
+        };
+        if weights.len() != size {
+            return Err(Error::ShapeMismatch {
+                expected: size,
+                found: weights.len(),
+            });
+        }
+        // a weight below 0 flips its term, all 0 leaves nothing to divide the mean by
+        if weights.iter().any(|w| !w.is_finite() || *w < 0f32) || weights.iter().all(|w| *w == 0f32)
+        {
+            return Err(Error::InvalidConfig(
+                "loss weights have to be finite, at least 0 and not all 0".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Categorical cross-entropy between a target distribution (usually one-hot) and predicted
+// probabilities. Put a `Softmax` at the end of the network to get the fused path.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub struct CrossEntropy;
+impl Loss for CrossEntropy {
+    fn loss(&self, truth: &[f32], prediction: &[f32]) -> f32 {
+        -truth
+            .iter()
+            .zip(prediction.iter())
+            .map(|(t, p)| t * p.max(EPSILON).ln())
+            .sum::<f32>()
+    }
+    fn loss_prime(&self, truth: &[f32], prediction: &[f32]) -> Vec<f32> {
+        truth
+            .iter()
+            .zip(prediction.iter())
+            .map(|(t, p)| -t / p.max(EPSILON))
+            .collect()
+    }
+    fn softmax_loss(&self, truth: &[f32], logits: &[f32]) -> Option<(f32, Vec<f32>)> {
+        // log_softmax(z) = z - max - ln(sum(exp(z - max)))
+        let max = logits.iter().fold(f32::NEG_INFINITY, |m, z| m.max(*z));
+        let log_sum = logits.iter().map(|z| (z - max).exp()).sum::<f32>().ln();
+        let mut loss = 0f32;
+        let mut gradient = Vec::with_capacity(logits.len());
+        let total: f32 = truth.iter().sum();
+        for (t, z) in truth.iter().zip(logits) {
+            let log_p = z - max - log_sum;
+            loss -= t * log_p;
+            // d/dz = softmax(z) * sum(t) - t, which is p - t for a proper distribution
+            gradient.push(log_p.exp() * total - t);
+        }
+        Some((loss, gradient))
+    }
+}
+
+// Binary cross-entropy on probabilities, e.g. after a `Sigmoid`. Targets can be multi-label.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct BinaryCrossEntropy {
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl ElementLoss for BinaryCrossEntropy {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        let p = p.clamp(EPSILON, 1f32 - EPSILON);
+        -(t * p.ln() + (1f32 - t) * (1f32 - p).ln())
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        let p = p.clamp(EPSILON, 1f32 - EPSILON);
+        (p - t) / (p * (1f32 - p))
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// Binary cross-entropy taking raw logits, with the sigmoid folded in so it cannot saturate.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct BinaryCrossEntropyWithLogits {
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl ElementLoss for BinaryCrossEntropyWithLogits {
+    fn element(&self, t: f32, x: f32) -> f32 {
+        // max(x, 0) - x * t + ln(1 + exp(-|x|))
+        x.max(0f32) - x * t + (-x.abs()).exp().ln_1p()
+    }
+    fn element_prime(&self, t: f32, x: f32) -> f32 {
+        1f32 / (1f32 + (-x).exp()) - t
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// Quadratic for errors up to `delta` and linear beyond, so outliers don't dominate.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Huber {
+    pub delta: f32,
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl Default for Huber {
+    fn default() -> Self {
+        Self {
+            delta: 1f32,
+            weights: None,
+            reduction: Reduction::Mean,
+        }
+    }
+}
+
+impl ElementLoss for Huber {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        let d = (p - t).abs();
+        if d <= self.delta {
+            0.5 * d * d
+        } else {
+            self.delta * (d - 0.5 * self.delta)
+        }
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        (p - t).clamp(-self.delta, self.delta)
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// Huber divided by `beta`: quadratic below `beta`, plain L1 above it.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SmoothL1 {
+    pub beta: f32,
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl Default for SmoothL1 {
+    fn default() -> Self {
+        Self {
+            beta: 1f32,
+            weights: None,
+            reduction: Reduction::Mean,
+        }
+    }
+}
+
+impl ElementLoss for SmoothL1 {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        let d = (p - t).abs();
+        if d < self.beta {
+            0.5 * d * d / self.beta
+        } else {
+            d - 0.5 * self.beta
+        }
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        ((p - t) / self.beta).clamp(-1f32, 1f32)
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// Mean absolute error (L1).
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct MAE {
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl ElementLoss for MAE {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        (p - t).abs()
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        if p > t {
+            1f32
+        } else if p < t {
+            -1f32
+        } else {
+            0f32
+        }
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// Margin loss for targets in {-1, 1}.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Hinge {
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl ElementLoss for Hinge {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        (1f32 - t * p).max(0f32)
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        if t * p < 1f32 {
+            -t
+        } else {
+            0f32
+        }
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SquaredHinge {
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl ElementLoss for SquaredHinge {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        (1f32 - t * p).max(0f32).powi(2)
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        -2f32 * t * (1f32 - t * p).max(0f32)
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// KL(truth || prediction) between two probability distributions. Summed over the classes by
+// default, like the other distribution losses.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct KLDivergence {
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl Default for KLDivergence {
+    fn default() -> Self {
+        Self {
+            weights: None,
+            reduction: Reduction::Sum,
+        }
+    }
+}
+
+impl ElementLoss for KLDivergence {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        if t <= 0f32 {
+            return 0f32;
+        }
+        t * (t.ln() - p.max(EPSILON).ln())
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        -t / p.max(EPSILON)
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// Negative log-likelihood of log-probabilities (the output of a `LogSoftmax`) against a one-hot
+// or soft target.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct NLL {
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl Default for NLL {
+    fn default() -> Self {
+        Self {
+            weights: None,
+            reduction: Reduction::Sum,
+        }
+    }
+}
+
+impl ElementLoss for NLL {
+    fn element(&self, t: f32, log_p: f32) -> f32 {
+        -t * log_p
+    }
+    fn element_prime(&self, t: f32, _log_p: f32) -> f32 {
+        -t
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+// Binary focal loss on probabilities. `gamma` down-weights the easy examples, `alpha` balances
+// the positive against the negative class.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Focal {
+    pub alpha: f32,
+    pub gamma: f32,
+    pub weights: Option<Vec<f32>>,
+    pub reduction: Reduction,
+}
+
+impl Default for Focal {
+    fn default() -> Self {
+        Self {
+            alpha: 0.25,
+            gamma: 2f32,
+            weights: None,
+            reduction: Reduction::Mean,
+        }
+    }
+}
+
+impl ElementLoss for Focal {
+    fn element(&self, t: f32, p: f32) -> f32 {
+        let p = p.clamp(EPSILON, 1f32 - EPSILON);
+        let pos = -self.alpha * t * (1f32 - p).powf(self.gamma) * p.ln();
+        let neg = -(1f32 - self.alpha) * (1f32 - t) * p.powf(self.gamma) * (1f32 - p).ln();
+        pos + neg
+    }
+    fn element_prime(&self, t: f32, p: f32) -> f32 {
+        let p = p.clamp(EPSILON, 1f32 - EPSILON);
+        let g = self.gamma;
+        let pos =
+            self.alpha * t * (g * (1f32 - p).powf(g - 1f32) * p.ln() - (1f32 - p).powf(g) / p);
+        let neg = -(1f32 - self.alpha)
+            * (1f32 - t)
+            * (g * p.powf(g - 1f32) * (1f32 - p).ln() - p.powf(g) / (1f32 - p));
+        pos + neg
+    }
+    fn weights(&self) -> Option<&[f32]> {
+        self.weights.as_deref()
+    }
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+}
+
+#[test]
+fn fused_softmax_cross_entropy_matches_jacobian() {
+    use crate::activations::{Activation, Softmax};
+    use crate::layer::LayerOutput;
+    use crate::tensor::Tensor;
+
+    let logits = vec![2f32, -1f32, 0.5f32, 3f32];
+    let truth = vec![0f32, 0f32, 1f32, 0f32];
+    let mut softmax = Softmax::default();
+    let probs = softmax
+        .f_prop(&LayerOutput::Dense(Tensor::new(logits.clone(), &[1, 4])))
+        .into_tensor();
+
+    let unfused = softmax.b_prop(&Tensor::new(
+        CrossEntropy.loss_prime(&truth, probs.as_slice()),
+        &[1, 4],
+    ));
+    let (loss, fused) = CrossEntropy.softmax_loss(&truth, &logits).unwrap();
+    assert!((loss - CrossEntropy.loss(&truth, probs.as_slice())).abs() < 1e-5);
+    for (a, b) in unfused.as_slice().iter().zip(fused.iter()) {
+        assert!((a - b).abs() < 1e-5);
+    }
+
+    // stays finite where the unfused loss would take ln(0)
+    let (loss, _) = CrossEntropy
+        .softmax_loss(&truth, &[1000f32, 0f32, -1000f32, 0f32])
+        .unwrap();
+    assert!(loss.is_finite() && loss > 1000f32);
+}
+
+#[test]
+fn element_losses_gradient_check() {
+    fn check(loss: impl Loss, truth: &[f32], prediction: &[f32]) {
+        let grad = loss.loss_prime(truth, prediction);
+        for i in 0..prediction.len() {
+            let h = 1e-3;
+            let mut plus = prediction.to_vec();
+            let mut minus = prediction.to_vec();
+            plus[i] += h;
+            minus[i] -= h;
+            let numeric = (loss.loss(truth, &plus) - loss.loss(truth, &minus)) / (2f32 * h);
+            assert!(
+                (numeric - grad[i]).abs() < 1e-2 * (1f32 + numeric.abs()),
+                "{} vs {} at {}",
+                numeric,
+                grad[i],
+                i
+            );
+        }
+    }
+    let truth = [1f32, 0f32, 1f32, 0f32];
+    let probs = [0.7f32, 0.2f32, 0.4f32, 0.6f32];
+    let signs = [1f32, -1f32, 1f32, -1f32];
+    let values = [0.3f32, -2.5f32, 0.6f32, 3f32];
+    let weights = Some(vec![1f32, 2f32, 0.5f32, 1f32]);
+
+    check(BinaryCrossEntropy::default(), &truth, &probs);
+    check(
+        BinaryCrossEntropy {
+            weights: weights.clone(),
+            reduction: Reduction::Sum,
+        },
+        &truth,
+        &probs,
+    );
+    check(BinaryCrossEntropyWithLogits::default(), &truth, &values);
+    check(Huber::default(), &truth, &values);
+    check(SmoothL1::default(), &truth, &values);
+    check(MAE::default(), &truth, &values);
+    check(Hinge::default(), &signs, &values);
+    check(SquaredHinge::default(), &signs, &values);
+    check(
+        KLDivergence::default(),
+        &[0.1f32, 0.2f32, 0.3f32, 0.4f32],
+        &probs,
+    );
+    check(NLL::default(), &truth, &values);
+    check(
+        Focal {
+            weights,
+            ..Default::default()
+        },
+        &truth,
+        &probs,
+    );
+
+    // the logits variant agrees with sigmoid + BCE
+    let sigmoid: Vec<f32> = values.iter().map(|x| 1f32 / (1f32 + (-x).exp())).collect();
+    let a = BinaryCrossEntropyWithLogits::default().loss(&truth, &values);
+    let b = BinaryCrossEntropy::default().loss(&truth, &sigmoid);
+    assert!((a - b).abs() < 1e-4);
+
+    let none = MAE {
+        weights: None,
+        reduction: Reduction::None,
+    };
+    let losses = none.losses(&truth, &values);
+    for (l, expected) in losses.iter().zip([0.7f32, 2.5f32, 0.4f32, 3f32]) {
+        assert!((l - expected).abs() < 1e-6);
+    }
+    assert!(matches!(none.check(4), Err(Error::InvalidConfig(_))));
+
+    // the weighted mean, with one weight per output
+    let weighted = MAE {
+        weights: Some(vec![1f32, 3f32, 0f32, 0f32]),
+        reduction: Reduction::Mean,
+    };
+    assert!((weighted.loss(&truth, &values) - (0.7 + 3f32 * 2.5) / 4f32).abs() < 1e-6);
+    assert!(weighted.check(4).is_ok());
+    assert!(matches!(
+        weighted.check(5),
+        Err(Error::ShapeMismatch {
+            expected: 5,
+            found: 4
+        })
+    ));
+    for weights in [
+        vec![0f32; 4],
+        vec![1f32, -1f32, 1f32, 1f32],
+        vec![1f32, f32::INFINITY, 1f32, 1f32],
+        vec![1f32, f32::NAN, 1f32, 1f32],
+    ] {
+        let loss = MAE {
+            weights: Some(weights),
+            reduction: Reduction::Sum,
+        };
+        assert!(matches!(loss.check(4), Err(Error::InvalidConfig(_))));
+    }
+}
+
+#[test]
+fn log_softmax_nll_matches_cross_entropy() {
+    use crate::activations::{Activation, LogSoftmax};
+    use crate::layer::LayerOutput;
+    use crate::tensor::Tensor;
+
+    let logits = vec![2f32, -1f32, 0.5f32, 3f32];
+    let truth = vec![0f32, 0f32, 1f32, 0f32];
+    let mut log_softmax = LogSoftmax::default();
+    let log_probs = log_softmax
+        .f_prop(&LayerOutput::Dense(Tensor::new(logits.clone(), &[1, 4])))
+        .into_tensor();
+    let gradient = log_softmax.b_prop(&Tensor::new(
+        NLL::default().loss_prime(&truth, log_probs.as_slice()),
+        &[1, 4],
+    ));
+    let (loss, expected) = CrossEntropy.softmax_loss(&truth, &logits).unwrap();
+    assert!((NLL::default().loss(&truth, log_probs.as_slice()) - loss).abs() < 1e-5);
+    for (a, b) in gradient.as_slice().iter().zip(expected.iter()) {
+        assert!((a - b).abs() < 1e-5);
+    }
+}
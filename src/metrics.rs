@@ -0,0 +1,264 @@
+use crate::data::{DataLoader, Dataset};
+use crate::error::{Error, Result};
+use crate::loss::Loss;
+use crate::network::Network;
+use scoped_threadpool::Pool;
+use std::fmt;
+
+// Scores averaged over the samples of a dataset.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Metric {
+    // the predicted class (largest output) is the target's, a single output counts as the
+    // probability of class 1
+    Accuracy,
+    MeanSquaredError,
+    MeanAbsoluteError,
+}
+
+impl Metric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Accuracy => "accuracy",
+            Metric::MeanSquaredError => "mse",
+            Metric::MeanAbsoluteError => "mae",
+        }
+    }
+
+    pub fn higher_is_better(&self) -> bool {
+        matches!(self, Metric::Accuracy)
+    }
+
+    // the score of one sample
+    pub fn sample(&self, truth: &[f32], prediction: &[f32]) -> f32 {
+        match self {
+            Metric::Accuracy => {
+                let hit = if prediction.len() == 1 {
+                    (prediction[0] >= 0.5) == (truth[0] >= 0.5)
+                } else {
+                    argmax(prediction) == argmax(truth)
+                };
+                if hit {
+                    1f32
+                } else {
+                    0f32
+                }
+            }
+            Metric::MeanSquaredError => {
+                let sum: f32 = truth
+                    .iter()
+                    .zip(prediction)
+                    .map(|(t, p)| (t - p).powi(2))
+                    .sum();
+                sum / truth.len() as f32
+            }
+            Metric::MeanAbsoluteError => {
+                let sum: f32 = truth
+                    .iter()
+                    .zip(prediction)
+                    .map(|(t, p)| (t - p).abs())
+                    .sum();
+                sum / truth.len() as f32
+            }
+        }
+    }
+}
+
+fn argmax(values: &[f32]) -> usize {
+    let mut best = 0;
+    for (i, v) in values.iter().enumerate() {
+        if *v > values[best] {
+            best = i;
+        }
+    }
+    best
+}
+
+// The average loss and `metrics` of `network` over `dataset`, predicted in batches of
+// `batch_size` split over `num_thread` threads.
+pub fn evaluate(
+    network: &Network,
+    loss_fn: &impl Loss,
+    dataset: &(impl Dataset + ?Sized),
+    metrics: &[Metric],
+    batch_size: usize,
+    num_thread: usize,
+) -> Result<(f32, Vec<f32>)> {
+    if num_thread == 0 {
+        return Err(Error::InvalidConfig("need at least one thread".into()));
+    }
+    let mut pool = Pool::new(num_thread as u32);
+    evaluate_pooled(network, loss_fn, dataset, metrics, batch_size, &mut pool)
+}
+
+// `evaluate` on the threads of `pool`, e.g. the trainer's after every epoch.
+pub(crate) fn evaluate_pooled(
+    network: &Network,
+    loss_fn: &impl Loss,
+    dataset: &(impl Dataset + ?Sized),
+    metrics: &[Metric],
+    batch_size: usize,
+    pool: &mut Pool,
+) -> Result<(f32, Vec<f32>)> {
+    if dataset.is_empty() {
+        return Err(Error::InvalidConfig("no samples to evaluate".into()));
+    }
+    let mut loss = 0f32;
+    let mut scores = vec![0f32; metrics.len()];
+    DataLoader::new(dataset, batch_size).for_each_batch(|batch| {
+        let predictions =
+            network.predict_batch_pooled(batch.inputs.as_slice(), batch.len(), pool)?;
+        let size = predictions.len() / batch.len();
+        loss_fn.check(size)?;
+        for (i, prediction) in predictions.chunks(size).enumerate() {
+            let truth = batch.targets.row(i);
+            if truth.len() != size {
+                return Err(Error::ShapeMismatch {
+                    expected: size,
+                    found: truth.len(),
+                });
+            }
+            loss += loss_fn.loss(truth, prediction);
+            for (score, metric) in scores.iter_mut().zip(metrics) {
+                *score += metric.sample(truth, prediction);
+            }
+        }
+        Ok(true)
+    })?;
+    let samples = dataset.len() as f32;
+    Ok((loss / samples, scores.iter().map(|s| s / samples).collect()))
+}
+
+// What training reports after every epoch.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Report {
+    pub epoch: usize,
+    // average training loss of the epoch
+    pub loss: f32,
+    // on the validation set, when there is one
+    pub val_loss: Option<f32>,
+    pub val_metrics: Vec<(Metric, f32)>,
+}
+
+impl Report {
+    pub fn get(&self, monitor: Monitor) -> Option<f32> {
+        match monitor {
+            Monitor::Loss => Some(self.loss),
+            Monitor::ValLoss => self.val_loss,
+            Monitor::Val(metric) => self
+                .val_metrics
+                .iter()
+                .find(|(m, _)| *m == metric)
+                .map(|(_, score)| *score),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "epoch: {} loss: {}", self.epoch + 1, self.loss)?;
+        if let Some(val_loss) = self.val_loss {
+            write!(f, " val_loss: {}", val_loss)?;
+        }
+        for (metric, score) in self.val_metrics.iter() {
+            write!(f, " val_{}: {}", metric.name(), score)?;
+        }
+        Ok(())
+    }
+}
+
+// A number in the `Report` to watch.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Monitor {
+    Loss,
+    ValLoss,
+    Val(Metric),
+}
+
+impl Monitor {
+    pub fn higher_is_better(&self) -> bool {
+        match self {
+            Monitor::Loss | Monitor::ValLoss => false,
+            Monitor::Val(metric) => metric.higher_is_better(),
+        }
+    }
+
+    // `value` beats `best` by more than `min_delta`
+    pub fn improves(&self, value: f32, best: f32, min_delta: f32) -> bool {
+        if self.higher_is_better() {
+            value > best + min_delta
+        } else {
+            value < best - min_delta
+        }
+    }
+}
+
+// Stops training once `monitor` went `patience` epochs without improving by more than
+// `min_delta`. With `restore_best` the network ends up with the weights of its best epoch, even
+// when training ran all its epochs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EarlyStopping {
+    pub monitor: Monitor,
+    pub patience: usize,
+    pub min_delta: f32,
+    pub restore_best: bool,
+}
+
+impl EarlyStopping {
+    pub fn new(monitor: Monitor, patience: usize) -> Self {
+        Self {
+            monitor,
+            patience,
+            min_delta: 0f32,
+            restore_best: true,
+        }
+    }
+}
+
+#[test]
+fn metrics_and_monitors() {
+    use crate::activations::{ActivationFn, Identity};
+    use crate::data::InMemory;
+    use crate::layer::{dense::DenseLayer, LayerType};
+    use crate::loss::MSE;
+    use crate::network::Net;
+
+    assert_eq!(
+        Metric::Accuracy.sample(&[0f32, 1f32, 0f32], &[0.2, 0.5, 0.3]),
+        1f32
+    );
+    assert_eq!(Metric::Accuracy.sample(&[1f32], &[0.3]), 0f32);
+    assert_eq!(
+        Metric::MeanAbsoluteError.sample(&[1f32, -1f32], &[0f32, 0f32]),
+        1f32
+    );
+
+    // the identity network predicts its input
+    let mut dense = DenseLayer::new(2, 2);
+    dense.weights = crate::tensor::Tensor::new(vec![1f32, 0f32, 0f32, 1f32], &[2, 2]);
+    let network = Network::new(vec![
+        Net::Layer(LayerType::Dense(dense)),
+        Net::Activation(ActivationFn::Identity(Identity::default())),
+    ]);
+    let dataset = InMemory::new(
+        vec![vec![1f32, 0f32], vec![0f32, 1f32], vec![0f32, 2f32]],
+        vec![vec![1f32, 0f32], vec![1f32, 0f32], vec![0f32, 1f32]],
+    )
+    .unwrap();
+    let metrics = [Metric::Accuracy, Metric::MeanSquaredError];
+    let (loss, scores) = evaluate(&network, &MSE, &dataset, &metrics, 2, 2).unwrap();
+    let expected_mse = (0f32 + 1f32 + 0.5) / 3f32;
+    assert!((scores[0] - 2f32 / 3f32).abs() < 1e-6);
+    assert!((scores[1] - expected_mse).abs() < 1e-6);
+    assert!(loss > 0f32);
+
+    let report = Report {
+        epoch: 0,
+        loss: 1f32,
+        val_loss: Some(2f32),
+        val_metrics: vec![(Metric::Accuracy, 0.5)],
+    };
+    assert_eq!(report.get(Monitor::Val(Metric::Accuracy)), Some(0.5));
+    assert_eq!(report.get(Monitor::Val(Metric::MeanAbsoluteError)), None);
+    assert!(Monitor::Val(Metric::Accuracy).improves(0.6, 0.5, 0.05));
+    assert!(!Monitor::ValLoss.improves(1.96, 2f32, 0.05));
+}
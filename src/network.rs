@@ -1,155 +1,518 @@
-use crate::activations::{Activation, ActivationFn};
-use crate::layer::{LayerOutput, LayerType};
+use crate::activations::ActivationFn;
+use crate::builder::{self, BuildError, NetworkBuilder, Shape};
+use crate::error::{Error, Result};
+use crate::layer::{LayerOutput, LayerType, Param};
+use crate::tensor::Tensor;
+use crate::workspace::Workspace;
+use scoped_threadpool::Pool;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::Write;
 use std::path::Path;
 
+mod legacy;
+
+// A step of the network. Layers and activations are both just modules, so they can come in any
+// order: a layer without an activation, several activations in a row, ...
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Net {
     Layer(LayerType),
     Activation(ActivationFn),
 }
 
+impl Net {
+    pub fn f_prop(&mut self, input: LayerOutput) -> LayerOutput {
+        match self {
+            Net::Layer(layer) => layer.f_prop(input),
+            Net::Activation(activation) => activation.as_activation_mut().f_prop(&input),
+        }
+    }
+
+    pub fn f_prop_ref(&self, input: LayerOutput) -> LayerOutput {
+        match self {
+            Net::Layer(layer) => layer.f_prop_ref(input),
+            Net::Activation(activation) => activation.as_activation().f_prop_ref(&input),
+        }
+    }
+
+    pub fn b_prop(&mut self, output_gradient: &Tensor) -> Tensor {
+        match self {
+            Net::Layer(layer) => layer.b_prop(output_gradient),
+            Net::Activation(activation) => activation.as_activation_mut().b_prop(output_gradient),
+        }
+    }
+
+    // One sample of `input_shape` for inference, see `Network::predict_into`.
+    pub(crate) fn infer(
+        &self,
+        input: &[f32],
+        output: &mut [f32],
+        cols: &mut [f32],
+        input_shape: Shape,
+    ) {
+        match self {
+            Net::Layer(layer) => layer.infer(input, output, cols),
+            Net::Activation(activation) => {
+                activation
+                    .as_activation()
+                    .infer(input, output, input_shape.channels())
+            }
+        }
+    }
+
+    pub fn params(&mut self) -> Vec<Param<'_>> {
+        match self {
+            Net::Layer(layer) => layer.params(),
+            Net::Activation(activation) => activation.params(),
+        }
+    }
+
+    pub fn zero_grad(&mut self) {
+        match self {
+            Net::Layer(layer) => layer.zero_grad(),
+            Net::Activation(activation) => activation.zero_grad(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct Network {
-    pub(crate) layers: Vec<LayerType>,
-    pub(crate) activations: Vec<ActivationFn>,
+    pub(crate) modules: Vec<Net>,
 }
 
 impl Network {
+    // the modules run in the given order
     pub fn new(net: Vec<Net>) -> Self {
-        let mut layers = Vec::new();
-        let mut activations = Vec::new();
-        for n in net {
-            match n {
-                Net::Layer(layer) => layers.push(layer),
-                Net::Activation(activation) => activations.push(activation),
+        Network { modules: net }
+    }
+
+    // Sizes every layer from `input_shape` and checks the architecture when built, see
+    // `NetworkBuilder`.
+    pub fn builder(input_shape: impl Into<Shape>) -> NetworkBuilder {
+        NetworkBuilder::new(input_shape)
+    }
+
+    pub fn predict(&mut self, input: &[f32]) -> Result<Vec<f32>> {
+        let prediction = self.forward(&Tensor::new(input.to_vec(), &[1, input.len()]))?;
+        Ok(prediction.into_vec())
+    }
+
+    pub fn predict_ref(&self, input: &[f32]) -> Result<Vec<f32>> {
+        let prediction = self.forward_ref(&Tensor::new(input.to_vec(), &[1, input.len()]))?;
+        Ok(prediction.into_vec())
+    }
+
+    // `predict_ref` into `out`, with every intermediate result kept in `ws`. Once `ws` was made
+    // for this network (see `Workspace::new`) nothing is allocated.
+    pub fn predict_into(&self, input: &[f32], ws: &mut Workspace, out: &mut [f32]) -> Result<()> {
+        let Workspace {
+            shapes,
+            buffers,
+            cols,
+        } = ws;
+        if !self.has_shapes(shapes) {
+            return Err(Error::InvalidConfig(
+                "the workspace was made for another network".into(),
+            ));
+        }
+        for (expected, found) in [
+            (shapes[0], input.len()),
+            (shapes[shapes.len() - 1], out.len()),
+        ] {
+            if expected.len() != found {
+                return Err(Error::ShapeMismatch {
+                    expected: expected.len(),
+                    found,
+                });
             }
         }
-        Network {
-            layers,
-            activations,
+        let [first, second] = buffers;
+        let (mut current, mut next) = (first, second);
+        current[..input.len()].copy_from_slice(input);
+        for (module, shape) in self.modules.iter().zip(shapes.windows(2)) {
+            let (input, output) = (&current[..shape[0].len()], &mut next[..shape[1].len()]);
+            module.infer(input, output, cols, shape[0]);
+            std::mem::swap(&mut current, &mut next);
         }
+        out.copy_from_slice(&current[..out.len()]);
+        Ok(())
     }
 
-    pub fn predict(&mut self, input: &Vec<f32>) -> Vec<f32> {
-        let mut output = LayerOutput::Dense(input.clone());
-        for (layer_type, activation_fn) in self.layers.iter_mut().zip(self.activations.iter_mut()) {
-            match layer_type {
-                LayerType::Dense(layer) => {
-                    match output {
-                        // currenty just flattening the vector. not sure if this is the proper way
-                        // to do it.
-                        LayerOutput::Conv(v) => {
-                            output = layer.f_prop(&v.into_iter().flatten().collect());
-                        }
-                        LayerOutput::Dense(v) => {
-                            output = layer.f_prop(&v);
-                        }
-                        _ => unreachable!(),
-                    }
-                }
-                LayerType::Conv(layer) => match output {
-                    LayerOutput::Conv(v) => output = layer.f_prop(&v),
-                    LayerOutput::Dense(v) => output = layer.f_prop(&vec![v]),
-                    _ => unreachable!(),
-                },
-            }
+    // Runs `batch` inputs laid out back to back in `inputs` ([batch, input_size]) through the
+    // network as one matrix per layer. The predictions come back the same way,
+    // [batch, output_size].
+    pub fn predict_batch(&self, inputs: &[f32], batch: usize) -> Result<Vec<f32>> {
+        self.predict_batch_threaded(inputs, batch, 1)
+    }
 
-            match activation_fn {
-                ActivationFn::Tanh(tanh) => {
-                    output = tanh.f_prop(&output);
-                }
-                ActivationFn::Sigmoid(sigmoid) => {
-                    output = sigmoid.f_prop(&output);
-                }
-                ActivationFn::Relu(relu) => {
-                    output = relu.f_prop(&output);
-                }
+    // Like `predict_batch`, with the batch split into one sub-batch per thread. Starts the
+    // threads on every call, see `predict_batch_pooled` to keep them.
+    pub fn predict_batch_threaded(
+        &self,
+        inputs: &[f32],
+        batch: usize,
+        num_thread: usize,
+    ) -> Result<Vec<f32>> {
+        if num_thread == 0 {
+            return Err(Error::InvalidConfig("need at least one thread".into()));
+        }
+        self.predict_batch_pooled(inputs, batch, &mut Pool::new(num_thread as u32))
+    }
+
+    // Like `predict_batch_threaded`, with one sub-batch per thread of `pool`.
+    pub fn predict_batch_pooled(
+        &self,
+        inputs: &[f32],
+        batch: usize,
+        pool: &mut Pool,
+    ) -> Result<Vec<f32>> {
+        let num_thread = pool.thread_count() as usize;
+        if batch == 0 {
+            if !inputs.is_empty() {
+                return Err(Error::InvalidConfig(format!(
+                    "{} inputs for a batch of 0",
+                    inputs.len()
+                )));
             }
+            return Ok(Vec::new());
+        }
+        // activations alone keep the size of their input
+        let (input_size, output_size) = match self.shapes()? {
+            Some((input, output)) => (input.len(), output.len()),
+            None => (inputs.len() / batch, inputs.len() / batch),
+        };
+        if inputs.len() != batch * input_size {
+            return Err(Error::ShapeMismatch {
+                expected: batch * input_size,
+                found: inputs.len(),
+            });
+        }
+        if num_thread == 1 || batch == 1 {
+            let input = Tensor::new(inputs.to_vec(), &[batch, input_size]);
+            return Ok(self.run(input).into_vec());
         }
 
-        match output {
-            LayerOutput::Conv(_) | LayerOutput::None => {
-                unreachable!("Last layer need to be a dense layer")
+        let per_thread = batch.div_ceil(num_thread);
+        let mut outputs = vec![0f32; batch * output_size];
+        pool.scoped(|s| {
+            for (input, output) in inputs
+                .chunks(per_thread * input_size)
+                .zip(outputs.chunks_mut(per_thread * output_size))
+            {
+                s.execute(move || {
+                    let rows = input.len() / input_size;
+                    let prediction = self.run(Tensor::new(input.to_vec(), &[rows, input_size]));
+                    output.copy_from_slice(prediction.as_slice());
+                });
             }
-            LayerOutput::Dense(prediction) => prediction,
+        });
+        Ok(outputs)
+    }
+
+    // The (input, output) shape of one sample. Checks that every module takes what the one
+    // before it outputs and that the network ends with a dense output. `None` when there is no
+    // layer to take the input size from.
+    // Nothing is allocated, it runs before every forward pass.
+    pub fn shapes(&self) -> Result<Option<(Shape, Shape)>> {
+        let Some(input) = self.input_shape() else {
+            return Ok(None);
+        };
+        let mut shape = input;
+        for (index, module) in self.modules.iter().enumerate() {
+            shape = builder::output_shape(module, shape, index)?;
+        }
+        if let Shape::Conv(..) = shape {
+            return Err(BuildError::OutputNotDense(shape).into());
+        }
+        Ok(Some((input, shape)))
+    }
+
+    // Like `shapes`, with the input of every module followed by the output of the last one.
+    pub(crate) fn module_shapes(&self) -> Result<Option<Vec<Shape>>> {
+        let Some(input) = self.input_shape() else {
+            return Ok(None);
+        };
+        let mut shapes = vec![input];
+        for (index, module) in self.modules.iter().enumerate() {
+            shapes.push(builder::output_shape(module, shapes[index], index)?);
         }
+        if let Some(&shape @ Shape::Conv(..)) = shapes.last() {
+            return Err(BuildError::OutputNotDense(shape).into());
+        }
+        Ok(Some(shapes))
     }
 
+    // the input shape of the first layer
+    fn input_shape(&self) -> Option<Shape> {
+        self.modules.iter().find_map(|m| match m {
+            Net::Layer(layer) => Some(input_shape(layer)),
+            Net::Activation(_) => None,
+        })
+    }
 
-    pub fn predict_ref(&self, input: &Vec<f32>) -> Vec<f32> {
-        let mut output = LayerOutput::Dense(input.clone());
-        for (layer_type, activation_fn) in self.layers.iter().zip(self.activations.iter()) {
-            match layer_type {
-                LayerType::Dense(layer) => {
-                    match output {
-                        // currenty just flattening the vector. not sure if this is the proper way
-                        // to do it.
-                        LayerOutput::Conv(v) => {
-                            output = layer.f_prop_ref(&v.into_iter().flatten().collect());
-                        }
-                        LayerOutput::Dense(v) => {
-                            output = layer.f_prop_ref(&v);
-                        }
-                        _ => unreachable!(),
-                    }
-                }
-                LayerType::Conv(layer) => match output {
-                    LayerOutput::Conv(v) => output = layer.f_prop_ref(&v),
-                    LayerOutput::Dense(v) => output = layer.f_prop_ref(&vec![v]),
-                    _ => unreachable!(),
+    // Whether `shapes` is what `module_shapes` gives, without allocating.
+    fn has_shapes(&self, shapes: &[Shape]) -> bool {
+        shapes.len() == self.modules.len() + 1
+            && self.input_shape() == Some(shapes[0])
+            && self.modules.iter().zip(shapes.windows(2)).enumerate().all(
+                |(index, (module, shape))| {
+                    builder::output_shape(module, shape[0], index).ok() == Some(shape[1])
                 },
-            }
+            )
+    }
 
-            match activation_fn {
-                ActivationFn::Tanh(tanh) => {
-                    output = tanh.f_prop_ref(&output);
-                }
-                ActivationFn::Sigmoid(sigmoid) => {
-                    output = sigmoid.f_prop_ref(&output);
-                }
-                ActivationFn::Relu(relu) => {
-                    output = relu.f_prop_ref(&output);
-                }
+    fn check_input(&self, input: &Tensor) -> Result<()> {
+        if let Some((shape, _)) = self.shapes()? {
+            let found = input.len() / input.shape()[0].max(1);
+            if input.ndim() != 2 || found != shape.len() {
+                return Err(Error::ShapeMismatch {
+                    expected: shape.len(),
+                    found,
+                });
             }
         }
+        Ok(())
+    }
 
-        match output {
-            LayerOutput::Conv(_) | LayerOutput::None => {
-                unreachable!("Last layer need to be a dense layer")
-            }
-            LayerOutput::Dense(prediction) => prediction,
+    // Runs a batch ([batch, input_size]) through the network, keeping what each layer needs for
+    // back propagation.
+    pub fn forward(&mut self, input: &Tensor) -> Result<Tensor> {
+        self.check_input(input)?;
+        let mut output = LayerOutput::Dense(input.clone());
+        for module in self.modules.iter_mut() {
+            output = module.f_prop(output);
         }
+        Ok(output.into_tensor())
+    }
+
+    pub fn forward_ref(&self, input: &Tensor) -> Result<Tensor> {
+        self.check_input(input)?;
+        Ok(self.run(input.clone()))
     }
 
-    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+    // `forward_ref` on an input that was already checked
+    fn run(&self, input: Tensor) -> Tensor {
+        let mut output = LayerOutput::Dense(input);
+        for module in self.modules.iter() {
+            output = module.f_prop_ref(output);
+        }
+        output.into_tensor()
+    }
+
+    // Back propagates the loss gradient ([batch, output_size]) of the last `forward` call. The
+    // parameter gradients are accumulated on the layers, see `Optimizer::step`.
+    pub fn backward(&mut self, loss_gradient: Tensor) {
+        self.backward_from(loss_gradient, false);
+    }
+
+    // The logits that went into the final softmax during the last `forward`, if the network
+    // ends with one.
+    pub fn softmax_logits(&self) -> Option<&Tensor> {
+        match self.modules.last() {
+            Some(Net::Activation(ActivationFn::Softmax(softmax))) => Some(softmax.logits()),
+            _ => None,
+        }
+    }
+
+    // Like `backward`, but the gradient is taken with respect to the logits of the final softmax
+    // (see `softmax_logits`), e.g. from a fused softmax + cross-entropy loss.
+    pub fn backward_logits(&mut self, logits_gradient: Tensor) -> Result<()> {
+        if self.softmax_logits().is_none() {
+            return Err(Error::InvalidConfig(
+                "the network does not end with a softmax".into(),
+            ));
+        }
+        self.backward_from(logits_gradient, true);
+        Ok(())
+    }
+
+    fn backward_from(&mut self, gradient: Tensor, skip_last_module: bool) {
+        let mut gradient = gradient;
+        let skip = usize::from(skip_last_module);
+        for module in self.modules.iter_mut().rev().skip(skip) {
+            gradient = module.b_prop(&gradient);
+        }
+    }
+
+    // Every trainable tensor of the network with its accumulated gradient, in module order.
+    pub fn params(&mut self) -> Vec<Param<'_>> {
+        self.modules.iter_mut().flat_map(|m| m.params()).collect()
+    }
+
+    pub fn zero_grad(&mut self) {
+        self.modules.iter_mut().for_each(|m| m.zero_grad());
+    }
+
+    // Adds the gradients of a replica of this network onto its own.
+    pub(crate) fn accumulate_grads(&mut self, replica: &mut Network) {
+        for (param, other) in self.params().into_iter().zip(replica.params()) {
+            *param.grad += other.grad;
+        }
+    }
+
+    // Overwrites the parameters of a replica of this network with its own.
+    pub(crate) fn copy_params_to(&mut self, replica: &mut Network) {
+        for (param, other) in self.params().into_iter().zip(replica.params()) {
+            other
+                .value
+                .as_mut_slice()
+                .copy_from_slice(param.value.as_slice());
+        }
+    }
+
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
         let mut file = fs::File::create(path)?;
         let serialized: Vec<u8> = serde_cbor::to_vec(&self)?;
         file.write_all(&serialized)?;
         Ok(())
     }
 
-    pub fn load_from_file(
-        &mut self,
-        path: impl AsRef<Path>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let f = fs::File::open(path)?;
-        let network: Network = serde_cbor::from_reader(f)?;
-        *self = network;
+    pub fn load_from_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        *self = Self::from_file(path)?;
         Ok(())
     }
 
-    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
-        let f = fs::File::open(path)?;
-        let network: Network = serde_cbor::from_reader(f)?;
-        Ok(network)
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::decode(&fs::read(path)?)
     }
 
-    pub fn from_slice(&mut self, slice: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let network: Network = serde_cbor::from_reader(slice)?;
-        *self = network;
+    pub fn from_slice(&mut self, slice: &[u8]) -> Result<()> {
+        *self = Self::decode(slice)?;
         Ok(())
     }
+
+    // What `save_to_file` writes, or a network saved before it was made of modules.
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        serde_cbor::from_slice(bytes).or_else(|e| {
+            serde_cbor::from_slice::<legacy::Legacy>(bytes)
+                .map_err(|_| Error::from(e))
+                .and_then(Network::try_from)
+        })
+    }
+}
+
+// the shape a layer unflattens its input to
+fn input_shape(layer: &LayerType) -> Shape {
+    match layer {
+        LayerType::Dense(layer) => Shape::Dense(layer.weights.shape()[1]),
+        LayerType::Conv(layer) => layer.input_shape.into(),
+        LayerType::MaxPool2D(layer) => layer.input_shape().into(),
+        LayerType::AvgPool2D(layer) => layer.input_shape().into(),
+        LayerType::GlobalAvgPool(layer) => layer.input_shape.into(),
+    }
+}
+
+#[test]
+fn network_runs_modules_in_order() {
+    use crate::activations::{Relu, Tanh};
+    use crate::layer::dense::DenseLayer;
+
+    // a layer without an activation, then two activations in a row
+    let mut network = Network::new(vec![
+        Net::Layer(LayerType::Dense(DenseLayer::new(3, 4))),
+        Net::Layer(LayerType::Dense(DenseLayer::new(4, 2))),
+        Net::Activation(ActivationFn::Relu(Relu::default())),
+        Net::Activation(ActivationFn::Tanh(Tanh::default())),
+    ]);
+    let input = Tensor::from_fn(&[2, 3], |i| i as f32 * 0.1);
+    let mut expected = input.clone();
+    for module in network.modules.iter() {
+        if let Net::Layer(LayerType::Dense(dense)) = module {
+            let mut out = expected.view().matmul(&dense.weights.t());
+            for sample in 0..out.shape()[0] {
+                for (o, b) in out.row_mut(sample).iter_mut().zip(dense.biases.as_slice()) {
+                    *o += b;
+                }
+            }
+            expected = out;
+        }
+    }
+    let expected = expected.map(|x| x.max(0f32).tanh());
+    let saved = serde_cbor::to_vec(&network).unwrap();
+    for output in [
+        network.forward_ref(&input).unwrap(),
+        network.forward(&input).unwrap(),
+    ] {
+        for (a, b) in output.as_slice().iter().zip(expected.as_slice()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+    // the inputs cached for back propagation are not saved
+    assert_eq!(serde_cbor::to_vec(&network).unwrap(), saved);
+    assert!(matches!(
+        network.predict(&[1f32, 2f32]),
+        Err(Error::ShapeMismatch {
+            expected: 3,
+            found: 2
+        })
+    ));
+
+    network.backward(Tensor::full(&[2, 2], 1f32));
+    assert_eq!(network.params().len(), 4);
+    assert!(matches!(
+        network.backward_logits(Tensor::full(&[2, 2], 1f32)),
+        Err(Error::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn predict_batch_matches_predict() {
+    use crate::activations::{Sigmoid, Softmax};
+    use crate::layer::dense::DenseLayer;
+
+    let network = Network::new(vec![
+        Net::Layer(LayerType::Dense(DenseLayer::new(4, 6))),
+        Net::Activation(ActivationFn::Sigmoid(Sigmoid::default())),
+        Net::Layer(LayerType::Dense(DenseLayer::new(6, 3))),
+        Net::Activation(ActivationFn::Softmax(Softmax::default())),
+    ]);
+    let batch = 11;
+    let inputs: Vec<f32> = (0..batch * 4).map(|i| (i as f32 * 0.3).sin()).collect();
+    let expected: Vec<f32> = inputs
+        .chunks(4)
+        .flat_map(|x| network.predict_ref(x).unwrap())
+        .collect();
+
+    for outputs in [
+        network.predict_batch(&inputs, batch).unwrap(),
+        network.predict_batch_threaded(&inputs, batch, 4).unwrap(),
+        network
+            .predict_batch_pooled(&inputs, batch, &mut Pool::new(3))
+            .unwrap(),
+    ] {
+        assert_eq!(outputs.len(), batch * 3);
+        for (a, b) in outputs.iter().zip(&expected) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+    assert!(matches!(
+        network.predict_batch(&inputs, batch + 1),
+        Err(Error::ShapeMismatch {
+            expected: 48,
+            found: 44
+        })
+    ));
+    assert_eq!(network.predict_batch(&[], 0).unwrap(), Vec::<f32>::new());
+}
+
+#[test]
+fn shipped_model_still_loads() {
+    // saved before networks were made of modules, the web demo loads it
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/models/mnist");
+    let network = Network::from_file(path).unwrap();
+    assert_eq!(
+        network.shapes().unwrap(),
+        Some((Shape::Dense(784), Shape::Dense(10)))
+    );
+    let prediction = network.predict_ref(&[0.5f32; 784]).unwrap();
+    assert!(prediction.iter().all(|p| (0f32..=1f32).contains(p)));
+
+    // and comes back the same in the current layout
+    let mut reloaded = Network::default();
+    reloaded
+        .from_slice(&serde_cbor::to_vec(&network).unwrap())
+        .unwrap();
+    assert_eq!(reloaded.predict_ref(&[0.5f32; 784]).unwrap(), prediction);
+    assert!(Network::default().from_slice(&[0xa0]).is_err());
 }
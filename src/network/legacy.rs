@@ -0,0 +1,77 @@
+// The layout networks were saved in before modules: every layer followed by its activation, with
+// nested vectors for the weights. Only read, so older files such as `models/mnist` still load.
+use super::{Net, Network};
+use crate::activations::{ActivationFn, Relu, Sigmoid, Tanh};
+use crate::error::{Error, Result};
+use crate::layer::{dense::DenseLayer, LayerType};
+use crate::tensor::Tensor;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub(super) struct Legacy {
+    layers: Vec<Layer>,
+    activations: Vec<Activation>,
+}
+
+#[derive(Deserialize)]
+enum Layer {
+    Dense(Dense),
+    // kernels per input channel and a bias per output position, nothing today's layer can hold
+    Conv(IgnoredAny),
+}
+
+#[derive(Deserialize)]
+struct Dense {
+    weights: Vec<Vec<f32>>, // [output_size][input_size]
+    biases: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+enum Activation {
+    Tanh(IgnoredAny),
+    Sigmoid(IgnoredAny),
+    Relu(IgnoredAny),
+}
+
+impl TryFrom<Legacy> for Network {
+    type Error = Error;
+
+    fn try_from(legacy: Legacy) -> Result<Self> {
+        if legacy.layers.len() != legacy.activations.len() {
+            return Err(Error::InvalidConfig(
+                "every layer of an old network needs an activation".into(),
+            ));
+        }
+        let mut modules = Vec::new();
+        for (layer, activation) in legacy.layers.into_iter().zip(legacy.activations) {
+            let Layer::Dense(Dense { weights, biases }) = layer else {
+                return Err(Error::InvalidConfig(
+                    "old convolution layers can't be loaded, retrain the network".into(),
+                ));
+            };
+            let (output_size, input_size) = (weights.len(), weights.first().map_or(0, Vec::len));
+            if biases.len() != output_size || weights.iter().any(|w| w.len() != input_size) {
+                return Err(Error::InvalidConfig(
+                    "ragged weights in an old network".into(),
+                ));
+            }
+            let weights = Tensor::new(weights.concat(), &[output_size, input_size]);
+            modules.push(Net::Layer(LayerType::Dense(DenseLayer {
+                input: Tensor::zeros(&[1, input_size]),
+                weight_grad: Tensor::zeros(weights.shape()),
+                bias_grad: Tensor::zeros(&[output_size]),
+                weights,
+                biases: Tensor::new(biases, &[output_size]),
+            })));
+            modules.push(Net::Activation(match activation {
+                Activation::Tanh(_) => ActivationFn::Tanh(Tanh::default()),
+                Activation::Sigmoid(_) => ActivationFn::Sigmoid(Sigmoid::default()),
+                Activation::Relu(_) => ActivationFn::Relu(Relu::default()),
+            }));
+        }
+        let network = Network::new(modules);
+        network.shapes()?;
+        Ok(network)
+    }
+}
@@ -0,0 +1,379 @@
+use crate::error::{Error, Result};
+use crate::layer::Param;
+use crate::tensor::Tensor;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub trait Optimizer {
+    // Updates every parameter from its accumulated gradient. `params` has to come in the same
+    // order on every call (`Network::params` guarantees that), since the per-parameter state is
+    // matched by position.
+    fn step(&mut self, params: Vec<Param>);
+    fn learning_rate(&self) -> f32;
+    fn set_learning_rate(&mut self, learning_rate: f32);
+
+    // Everything `load_state` needs to carry on exactly where this optimizer is, hyperparameters
+    // and per-parameter state, for checkpoints. Optimizers that can't be saved return an error.
+    fn save_state(&self) -> Result<Vec<u8>> {
+        Err(Error::InvalidConfig(
+            "this optimizer can't save its state".into(),
+        ))
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> Result<()> {
+        Err(Error::InvalidConfig(
+            "this optimizer can't load a state".into(),
+        ))
+    }
+}
+
+fn save_state(optimizer: &impl Serialize) -> Result<Vec<u8>> {
+    Ok(serde_cbor::to_vec(optimizer)?)
+}
+
+fn load_state<O: DeserializeOwned>(optimizer: &mut O, state: &[u8]) -> Result<()> {
+    *optimizer = serde_cbor::from_slice(state)?;
+    Ok(())
+}
+
+// Returns the state tensor of the `i`th parameter, allocating it on first use.
+fn state<'a>(states: &'a mut Vec<Tensor>, i: usize, shape: &[usize]) -> &'a mut Tensor {
+    if states.len() <= i {
+        states.resize(i + 1, Tensor::default());
+    }
+    if states[i].shape() != shape {
+        states[i] = Tensor::zeros(shape);
+    }
+    &mut states[i]
+}
+
+// Stochastic gradient descent, optionally with (Nesterov) momentum and L2 weight decay.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SGD {
+    pub learning_rate: f32,
+    pub momentum: f32,
+    pub nesterov: bool,
+    pub weight_decay: f32,
+    velocity: Vec<Tensor>,
+}
+
+impl SGD {
+    pub fn new(learning_rate: f32) -> Self {
+        Self::momentum(learning_rate, 0f32)
+    }
+
+    pub fn momentum(learning_rate: f32, momentum: f32) -> Self {
+        Self {
+            learning_rate,
+            momentum,
+            nesterov: false,
+            weight_decay: 0f32,
+            velocity: Vec::new(),
+        }
+    }
+
+    pub fn nesterov(learning_rate: f32, momentum: f32) -> Self {
+        Self {
+            nesterov: true,
+            ..Self::momentum(learning_rate, momentum)
+        }
+    }
+}
+
+impl Optimizer for SGD {
+    fn step(&mut self, params: Vec<Param>) {
+        for (i, param) in params.into_iter().enumerate() {
+            let (lr, mu, wd) = (self.learning_rate, self.momentum, self.weight_decay);
+            if mu == 0f32 {
+                for (w, g) in param
+                    .value
+                    .as_mut_slice()
+                    .iter_mut()
+                    .zip(param.grad.as_slice())
+                {
+                    *w -= lr * (g + wd * *w);
+                }
+                continue;
+            }
+            let v = state(&mut self.velocity, i, param.value.shape()).as_mut_slice();
+            for ((w, g), v) in param
+                .value
+                .as_mut_slice()
+                .iter_mut()
+                .zip(param.grad.as_slice())
+                .zip(v.iter_mut())
+            {
+                let g = g + wd * *w;
+                // v = mu * v + g
+                *v = mu * *v + g;
+                // nesterov looks ahead along the updated velocity
+                let update = if self.nesterov { g + mu * *v } else { *v };
+                *w -= lr * update;
+            }
+        }
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f32) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>> {
+        save_state(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        load_state(self, state)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct RMSProp {
+    pub learning_rate: f32,
+    pub alpha: f32,
+    pub epsilon: f32,
+    pub weight_decay: f32,
+    square_avg: Vec<Tensor>,
+}
+
+impl RMSProp {
+    pub fn new(learning_rate: f32) -> Self {
+        Self {
+            learning_rate,
+            alpha: 0.99,
+            epsilon: 1e-8,
+            weight_decay: 0f32,
+            square_avg: Vec::new(),
+        }
+    }
+}
+
+impl Optimizer for RMSProp {
+    fn step(&mut self, params: Vec<Param>) {
+        for (i, param) in params.into_iter().enumerate() {
+            let s = state(&mut self.square_avg, i, param.value.shape()).as_mut_slice();
+            for ((w, g), s) in param
+                .value
+                .as_mut_slice()
+                .iter_mut()
+                .zip(param.grad.as_slice())
+                .zip(s.iter_mut())
+            {
+                let g = g + self.weight_decay * *w;
+                *s = self.alpha * *s + (1f32 - self.alpha) * g * g;
+                *w -= self.learning_rate * g / (s.sqrt() + self.epsilon);
+            }
+        }
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f32) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>> {
+        save_state(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        load_state(self, state)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Adagrad {
+    pub learning_rate: f32,
+    pub epsilon: f32,
+    pub weight_decay: f32,
+    square_sum: Vec<Tensor>,
+}
+
+impl Adagrad {
+    pub fn new(learning_rate: f32) -> Self {
+        Self {
+            learning_rate,
+            epsilon: 1e-10,
+            weight_decay: 0f32,
+            square_sum: Vec::new(),
+        }
+    }
+}
+
+impl Optimizer for Adagrad {
+    fn step(&mut self, params: Vec<Param>) {
+        for (i, param) in params.into_iter().enumerate() {
+            let s = state(&mut self.square_sum, i, param.value.shape()).as_mut_slice();
+            for ((w, g), s) in param
+                .value
+                .as_mut_slice()
+                .iter_mut()
+                .zip(param.grad.as_slice())
+                .zip(s.iter_mut())
+            {
+                let g = g + self.weight_decay * *w;
+                *s += g * g;
+                *w -= self.learning_rate * g / (s.sqrt() + self.epsilon);
+            }
+        }
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f32) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>> {
+        save_state(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        load_state(self, state)
+    }
+}
+
+// Adam with L2 regularization folded into the gradient. See `AdamW` for decoupled weight decay.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Adam {
+    pub learning_rate: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub epsilon: f32,
+    pub weight_decay: f32,
+    t: i32,
+    m: Vec<Tensor>,
+    v: Vec<Tensor>,
+}
+
+impl Adam {
+    pub fn new(learning_rate: f32) -> Self {
+        Self {
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+            weight_decay: 0f32,
+            t: 0,
+            m: Vec::new(),
+            v: Vec::new(),
+        }
+    }
+
+    // `l2` is added to the gradient, `decoupled` is applied straight to the weights
+    fn update(&mut self, params: Vec<Param>, l2: f32, decoupled: f32) {
+        self.t += 1;
+        let bias1 = 1f32 - self.beta1.powi(self.t);
+        let bias2 = 1f32 - self.beta2.powi(self.t);
+        for (i, param) in params.into_iter().enumerate() {
+            let shape = param.value.shape().to_vec();
+            state(&mut self.m, i, &shape);
+            state(&mut self.v, i, &shape);
+            for (((w, g), m), v) in param
+                .value
+                .as_mut_slice()
+                .iter_mut()
+                .zip(param.grad.as_slice())
+                .zip(self.m[i].as_mut_slice())
+                .zip(self.v[i].as_mut_slice())
+            {
+                let g = g + l2 * *w;
+                *m = self.beta1 * *m + (1f32 - self.beta1) * g;
+                *v = self.beta2 * *v + (1f32 - self.beta2) * g * g;
+                let m_hat = *m / bias1;
+                let v_hat = *v / bias2;
+                *w -= self.learning_rate * (m_hat / (v_hat.sqrt() + self.epsilon) + decoupled * *w);
+            }
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self, params: Vec<Param>) {
+        self.update(params, self.weight_decay, 0f32);
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f32) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>> {
+        save_state(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        load_state(self, state)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct AdamW(Adam);
+
+impl AdamW {
+    pub fn new(learning_rate: f32) -> Self {
+        Self::with_weight_decay(learning_rate, 0.01)
+    }
+
+    pub fn with_weight_decay(learning_rate: f32, weight_decay: f32) -> Self {
+        AdamW(Adam {
+            weight_decay,
+            ..Adam::new(learning_rate)
+        })
+    }
+}
+
+impl Optimizer for AdamW {
+    fn step(&mut self, params: Vec<Param>) {
+        self.0.update(params, 0f32, self.0.weight_decay);
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.0.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f32) {
+        self.0.learning_rate = learning_rate;
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>> {
+        save_state(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        load_state(self, state)
+    }
+}
+
+#[test]
+fn optimizers_minimize_quadratic() {
+    // f(w) = sum((w - 3)^2)
+    fn run(mut optimizer: impl Optimizer, steps: usize) -> f32 {
+        let mut w = Tensor::zeros(&[4]);
+        for _ in 0..steps {
+            let mut grad = w.map(|x| 2f32 * (x - 3f32));
+            optimizer.step(vec![Param::new(&mut w, &mut grad)]);
+        }
+        w.as_slice()
+            .iter()
+            .map(|x| (x - 3f32).abs())
+            .fold(0f32, f32::max)
+    }
+    assert!(run(SGD::new(0.1), 200) < 1e-3);
+    assert!(run(SGD::momentum(0.05, 0.9), 400) < 1e-3);
+    assert!(run(SGD::nesterov(0.05, 0.9), 400) < 1e-3);
+    assert!(run(RMSProp::new(0.01), 2000) < 1e-2);
+    assert!(run(Adagrad::new(0.5), 2000) < 1e-2);
+    assert!(run(Adam::new(0.1), 1000) < 1e-2);
+    assert!(run(AdamW::with_weight_decay(0.1, 0f32), 1000) < 1e-2);
+}
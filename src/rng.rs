@@ -0,0 +1,105 @@
+// Where the library gets its randomness when none is passed in. Every constructor that draws
+// numbers also has a variant taking any `Rng`, this is only the default behind them.
+//
+// Without a seed the generators come from the OS. After `seed` they are derived from it, one
+// ChaCha stream per call, so the same program makes the same draws in the same order. The seed
+// belongs to the calling thread, which is where networks are built and training is driven.
+//
+// Nothing else seeds it: `Trainer::seed` only fixes the data order. For reproducible weights
+// without touching this, give the builder its own generator with `NetworkBuilder::seed` (or
+// `NetworkBuilder::rng`), or build the layers with their `with_rng` constructors.
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::cell::Cell;
+
+thread_local! {
+    // (seed, next stream)
+    static SEED: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+pub fn seed(seed: u64) {
+    SEED.with(|s| s.set(Some((seed, 0))));
+}
+
+// Back to drawing from the OS.
+pub fn unseed() {
+    SEED.with(|s| s.set(None));
+}
+
+// A new generator, the next stream of the seed if there is one.
+pub fn rng() -> ChaCha8Rng {
+    match SEED.with(|s| s.get()) {
+        Some((seed, stream)) => {
+            SEED.with(|s| s.set(Some((seed, stream + 1))));
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            rng.set_stream(stream);
+            rng
+        }
+        None => ChaCha8Rng::from_entropy(),
+    }
+}
+
+#[test]
+fn seeded_runs_are_identical() {
+    use crate::activations::{ActivationFn, Relu, Softmax};
+    use crate::data::{DataLoader, InMemory};
+    use crate::loss::CrossEntropy;
+    use crate::network::Network;
+    use crate::optim::SGD;
+    use crate::trainer::Trainer;
+    use rand::Rng;
+
+    let xs: Vec<Vec<f32>> = (0..40)
+        .map(|i| (0..16).map(|j| ((i * 16 + j) as f32 * 0.3).sin()).collect())
+        .collect();
+    let ys: Vec<Vec<f32>> = (0..40)
+        .map(|i| {
+            (0..3)
+                .map(|j| if i % 3 == j { 1f32 } else { 0f32 })
+                .collect()
+        })
+        .collect();
+    let dataset = InMemory::new(xs, ys).unwrap();
+    // (network, trainer, loader) seeds
+    let run = |weights: u64, seed: u64, order: Option<u64>| {
+        let mut loader = DataLoader::new(&dataset, 8).shuffle(true);
+        if let Some(order) = order {
+            loader = loader.seed(order);
+        }
+        let mut network = Network::builder((1, 4, 4))
+            .seed(weights)
+            .conv((2, 3))
+            .activation(ActivationFn::Relu(Relu::default()))
+            .dense(3)
+            .activation(ActivationFn::Softmax(Softmax::default()))
+            .build()
+            .unwrap();
+        Trainer::new()
+            .threads(2)
+            .seed(seed)
+            .fit(
+                &mut network,
+                CrossEntropy,
+                &mut loader,
+                &mut SGD::new(0.1),
+                3,
+            )
+            .unwrap();
+        serde_cbor::to_vec(&network).unwrap()
+    };
+    assert_eq!(run(1, 1, None), run(1, 1, None));
+    assert_ne!(run(1, 1, None), run(1, 2, None));
+    // the loader's own seed wins, and the trainer leaves the thread's seed alone
+    assert_eq!(run(1, 1, Some(3)), run(1, 2, Some(3)));
+    seed(5);
+    run(1, 1, None);
+    assert_eq!(SEED.with(|s| s.get()).map(|(seed, _)| seed), Some(5));
+
+    // every call to `rng` is a new stream of the same seed
+    seed(5);
+    let (a, b) = (rng().gen::<u64>(), rng().gen::<u64>());
+    seed(5);
+    assert_eq!(rng().gen::<u64>(), a);
+    assert_ne!(a, b);
+    unseed();
+}
@@ -0,0 +1,426 @@
+use crate::gemm::{self, Matrix};
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, AddAssign, Mul, MulAssign, Sub, SubAssign};
+
+/// Contiguous, row-major n-dimensional array of `f32`.
+#[derive(Default, Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Tensor {
+    data: Vec<f32>,
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+}
+
+/// Borrowed, possibly strided (e.g. transposed) window into a `Tensor`.
+#[derive(Debug, Clone)]
+pub struct TensorView<'a> {
+    data: &'a [f32],
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+}
+
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+fn offset(shape: &[usize], strides: &[usize], index: &[usize]) -> usize {
+    assert_eq!(
+        index.len(),
+        shape.len(),
+        "index {:?} does not match shape {:?}",
+        index,
+        shape
+    );
+    index
+        .iter()
+        .zip(shape.iter().zip(strides.iter()))
+        .map(|(i, (dim, stride))| {
+            assert!(
+                i < dim,
+                "index {:?} out of bounds for shape {:?}",
+                index,
+                shape
+            );
+            i * stride
+        })
+        .sum()
+}
+
+impl Tensor {
+    pub fn new(data: Vec<f32>, shape: &[usize]) -> Self {
+        assert_eq!(
+            data.len(),
+            shape.iter().product::<usize>(),
+            "{} values do not fit shape {:?}",
+            data.len(),
+            shape
+        );
+        Self {
+            data,
+            shape: shape.to_vec(),
+            strides: contiguous_strides(shape),
+        }
+    }
+
+    pub fn zeros(shape: &[usize]) -> Self {
+        Self::full(shape, 0f32)
+    }
+
+    pub fn full(shape: &[usize], value: f32) -> Self {
+        Self::new(vec![value; shape.iter().product()], shape)
+    }
+
+    pub fn from_fn(shape: &[usize], f: impl FnMut(usize) -> f32) -> Self {
+        Self::new((0..shape.iter().product()).map(f).collect(), shape)
+    }
+
+    /// Stacks equally sized rows into a `[rows, row_len]` tensor.
+    pub fn from_rows<R: AsRef<[f32]>>(rows: &[R]) -> Self {
+        let row_len = rows.first().map_or(0, |r| r.as_ref().len());
+        let mut data = Vec::with_capacity(rows.len() * row_len);
+        for row in rows {
+            assert_eq!(row.as_ref().len(), row_len, "rows have different lengths");
+            data.extend_from_slice(row.as_ref());
+        }
+        Self::new(data, &[rows.len(), row_len])
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    /// Reinterprets the same buffer with a new shape. The number of elements must not change.
+    pub fn reshape(mut self, shape: &[usize]) -> Self {
+        assert_eq!(
+            self.data.len(),
+            shape.iter().product::<usize>(),
+            "cannot reshape {:?} into {:?}",
+            self.shape,
+            shape
+        );
+        self.shape = shape.to_vec();
+        self.strides = contiguous_strides(shape);
+        self
+    }
+
+    pub fn get(&self, index: &[usize]) -> f32 {
+        self.data[offset(&self.shape, &self.strides, index)]
+    }
+
+    pub fn get_mut(&mut self, index: &[usize]) -> &mut f32 {
+        let i = offset(&self.shape, &self.strides, index);
+        &mut self.data[i]
+    }
+
+    /// The `i`th sub-tensor along the first axis, as a flat slice.
+    pub fn row(&self, i: usize) -> &[f32] {
+        let stride = self.strides[0];
+        &self.data[i * stride..(i + 1) * stride]
+    }
+
+    pub fn row_mut(&mut self, i: usize) -> &mut [f32] {
+        let stride = self.strides[0];
+        &mut self.data[i * stride..(i + 1) * stride]
+    }
+
+    pub fn view(&self) -> TensorView<'_> {
+        TensorView {
+            data: &self.data,
+            shape: self.shape.clone(),
+            strides: self.strides.clone(),
+        }
+    }
+
+    /// Transposed view of a 2d tensor. No data is copied.
+    pub fn t(&self) -> TensorView<'_> {
+        self.view().t()
+    }
+
+    pub fn map(&self, f: impl Fn(f32) -> f32) -> Tensor {
+        Tensor {
+            data: self.data.iter().map(|x| f(*x)).collect(),
+            shape: self.shape.clone(),
+            strides: self.strides.clone(),
+        }
+    }
+
+    pub fn map_inplace(&mut self, f: impl Fn(f32) -> f32) {
+        self.data.iter_mut().for_each(|x| *x = f(*x));
+    }
+
+    pub fn zip_map(&self, other: &Tensor, f: impl Fn(f32, f32) -> f32) -> Tensor {
+        assert_eq!(
+            self.shape, other.shape,
+            "elementwise op on mismatched shapes"
+        );
+        Tensor {
+            data: self
+                .data
+                .iter()
+                .zip(other.data.iter())
+                .map(|(a, b)| f(*a, *b))
+                .collect(),
+            shape: self.shape.clone(),
+            strides: self.strides.clone(),
+        }
+    }
+
+    pub fn fill(&mut self, value: f32) {
+        self.data.iter_mut().for_each(|x| *x = value);
+    }
+
+    pub fn sum(&self) -> f32 {
+        self.data.iter().sum()
+    }
+
+    /// Sums over the first axis, e.g. `[batch, n] -> [n]`.
+    pub fn sum_axis0(&self) -> Tensor {
+        let mut out = Tensor::zeros(&self.shape[1..]);
+        for i in 0..self.shape[0] {
+            for (o, x) in out.data.iter_mut().zip(self.row(i)) {
+                *o += x;
+            }
+        }
+        out
+    }
+
+    pub fn dot(&self, other: &Tensor) -> f32 {
+        assert_eq!(self.len(), other.len());
+        self.data
+            .iter()
+            .zip(other.data.iter())
+            .map(|(a, b)| a * b)
+            .sum()
+    }
+
+    /// 2d matrix product `[m, k] x [k, n] -> [m, n]`.
+    pub fn matmul(&self, rhs: &Tensor) -> Tensor {
+        self.view().matmul(&rhs.view())
+    }
+}
+
+impl<'a> TensorView<'a> {
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn get(&self, index: &[usize]) -> f32 {
+        self.data[offset(&self.shape, &self.strides, index)]
+    }
+
+    pub fn is_contiguous(&self) -> bool {
+        self.strides == contiguous_strides(&self.shape)
+    }
+
+    pub fn reshape(self, shape: &[usize]) -> TensorView<'a> {
+        assert!(
+            self.is_contiguous(),
+            "only contiguous views can be reshaped"
+        );
+        assert_eq!(
+            self.shape.iter().product::<usize>(),
+            shape.iter().product::<usize>(),
+            "cannot reshape {:?} into {:?}",
+            self.shape,
+            shape
+        );
+        TensorView {
+            data: self.data,
+            shape: shape.to_vec(),
+            strides: contiguous_strides(shape),
+        }
+    }
+
+    pub fn t(mut self) -> TensorView<'a> {
+        assert_eq!(self.shape.len(), 2, "transpose needs a 2d tensor");
+        self.shape.swap(0, 1);
+        self.strides.swap(0, 1);
+        self
+    }
+
+    /// The `i`th sub-tensor along the first axis.
+    pub fn at(&self, i: usize) -> TensorView<'a> {
+        assert!(i < self.shape[0]);
+        TensorView {
+            data: &self.data[i * self.strides[0]..],
+            shape: self.shape[1..].to_vec(),
+            strides: self.strides[1..].to_vec(),
+        }
+    }
+
+    pub fn to_tensor(&self) -> Tensor {
+        if self.is_contiguous() {
+            return Tensor::new(
+                self.data[..self.shape.iter().product()].to_vec(),
+                &self.shape,
+            );
+        }
+        let mut data = Vec::with_capacity(self.shape.iter().product());
+        let mut index = vec![0; self.shape.len()];
+        for _ in 0..self.shape.iter().product() {
+            data.push(self.get(&index));
+            for axis in (0..index.len()).rev() {
+                index[axis] += 1;
+                if index[axis] < self.shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+        Tensor::new(data, &self.shape)
+    }
+
+    pub fn matmul(&self, rhs: &TensorView) -> Tensor {
+        let mut out = Tensor::zeros(&[self.shape[0], rhs.shape[1]]);
+        self.matmul_into(rhs, &mut out);
+        out
+    }
+
+    /// `out += self x rhs` for an `out` of shape `[m, n]`, without allocating.
+    pub fn matmul_into(&self, rhs: &TensorView, out: &mut Tensor) {
+        assert!(
+            self.shape.len() == 2 && rhs.shape.len() == 2 && self.shape[1] == rhs.shape[0],
+            "cannot multiply {:?} by {:?}",
+            self.shape,
+            rhs.shape
+        );
+        assert_eq!(out.shape(), &[self.shape[0], rhs.shape[1]]);
+        self.matmul_acc(rhs, out.as_mut_slice());
+    }
+
+    // `matmul_into` on a plain `[m, n]` slice, e.g. one sample of a batched tensor
+    pub(crate) fn matmul_acc(&self, rhs: &TensorView, out: &mut [f32]) {
+        gemm::gemm(self.matrix(), rhs.matrix(), out);
+    }
+
+    fn matrix(&self) -> Matrix<'_> {
+        Matrix {
+            data: self.data,
+            rows: self.shape[0],
+            cols: self.shape[1],
+            row_stride: self.strides[0],
+            col_stride: self.strides[1],
+        }
+    }
+}
+
+impl Add for &Tensor {
+    type Output = Tensor;
+    fn add(self, rhs: &Tensor) -> Tensor {
+        self.zip_map(rhs, |a, b| a + b)
+    }
+}
+
+impl Sub for &Tensor {
+    type Output = Tensor;
+    fn sub(self, rhs: &Tensor) -> Tensor {
+        self.zip_map(rhs, |a, b| a - b)
+    }
+}
+
+impl Mul for &Tensor {
+    type Output = Tensor;
+    fn mul(self, rhs: &Tensor) -> Tensor {
+        self.zip_map(rhs, |a, b| a * b)
+    }
+}
+
+impl Mul<f32> for &Tensor {
+    type Output = Tensor;
+    fn mul(self, rhs: f32) -> Tensor {
+        self.map(|a| a * rhs)
+    }
+}
+
+impl AddAssign<&Tensor> for Tensor {
+    fn add_assign(&mut self, rhs: &Tensor) {
+        assert_eq!(self.shape, rhs.shape, "elementwise op on mismatched shapes");
+        self.data
+            .iter_mut()
+            .zip(rhs.data.iter())
+            .for_each(|(a, b)| *a += b);
+    }
+}
+
+impl SubAssign<&Tensor> for Tensor {
+    fn sub_assign(&mut self, rhs: &Tensor) {
+        assert_eq!(self.shape, rhs.shape, "elementwise op on mismatched shapes");
+        self.data
+            .iter_mut()
+            .zip(rhs.data.iter())
+            .for_each(|(a, b)| *a -= b);
+    }
+}
+
+impl MulAssign<f32> for Tensor {
+    fn mul_assign(&mut self, rhs: f32) {
+        self.map_inplace(|a| a * rhs);
+    }
+}
+
+#[test]
+fn tensor_reshape_and_views() {
+    let t = Tensor::from_fn(&[2, 3], |i| i as f32);
+    assert_eq!(t.strides(), &[3, 1]);
+    assert_eq!(t.get(&[1, 2]), 5f32);
+    assert_eq!(t.row(1), &[3f32, 4f32, 5f32]);
+
+    let tt = t.t();
+    assert_eq!(tt.shape(), &[3, 2]);
+    assert!(!tt.is_contiguous());
+    assert_eq!(
+        tt.to_tensor().as_slice(),
+        &[0f32, 3f32, 1f32, 4f32, 2f32, 5f32]
+    );
+
+    let r = t.reshape(&[3, 2]);
+    assert_eq!(r.view().at(2).to_tensor().as_slice(), &[4f32, 5f32]);
+}
+
+#[test]
+fn tensor_matmul() {
+    let a = Tensor::new(vec![1f32, 2f32, 3f32, 4f32, 5f32, 6f32], &[2, 3]);
+    let b = Tensor::new(vec![7f32, 8f32, 9f32, 10f32, 11f32, 12f32], &[3, 2]);
+    assert_eq!(a.matmul(&b).as_slice(), &[58f32, 64f32, 139f32, 154f32]);
+    // a . a^T through a strided view
+    assert_eq!(
+        a.view().matmul(&a.t()).as_slice(),
+        &[14f32, 32f32, 32f32, 77f32]
+    );
+    assert_eq!((&a + &a).as_slice(), (&a * 2f32).as_slice());
+}
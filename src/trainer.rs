@@ -1,128 +1,772 @@
-use crate::{
-    activations::{Activation, ActivationFn},
-    layer::LayerType,
-    loss::Loss,
-    network::Network,
-};
+use crate::callbacks::{Callback, Context, Progress, SaveNetwork};
+use crate::checkpoint::{Checkpoint, Checkpointer};
+use crate::data::{Batch, DataLoader, Dataset};
+use crate::error::{Error, Result};
+use crate::metrics::{self, EarlyStopping, Metric, Monitor, Report};
+use crate::{loss::Loss, network::Network, optim::Optimizer, tensor::Tensor};
 use num_cpus;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 use scoped_threadpool::Pool;
-use std::sync::mpsc;
+use std::ops::Range;
 
-pub struct Trainer;
+// How to train, `Trainer::cpu` runs with the defaults.
+//
+//     Trainer::new()
+//         .threads(4)
+//         .seed(42)
+//         .validation(&val_set)
+//         .metrics(&[Metric::Accuracy])
+//         .early_stopping(EarlyStopping::new(Monitor::ValLoss, 3))
+//         .callback(Progress::new())
+//         .fit(&mut network, MSE, &mut loader, &mut optimizer, 10)?;
+pub struct Trainer<'a> {
+    num_thread: usize,
+    seed: Option<u64>,
+    validation: Option<&'a dyn Dataset>,
+    metrics: Vec<Metric>,
+    early_stopping: Option<EarlyStopping>,
+    checkpointer: Option<Checkpointer>,
+    resume: Option<Checkpoint>,
+    callbacks: Vec<Box<dyn Callback + 'a>>,
+}
+
+impl Default for Trainer<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Trainer<'a> {
+    // one thread per cpu, no seed, no validation
+    pub fn new() -> Self {
+        Self {
+            num_thread: num_cpus::get(),
+            seed: None,
+            validation: None,
+            metrics: Vec::new(),
+            early_stopping: None,
+            checkpointer: None,
+            resume: None,
+            callbacks: Vec::new(),
+        }
+    }
+
+    // The number of replicas a mini-batch is split over. The summed gradients are the same for
+    // any count, but floats add up in a different order, so bit for bit reproducible runs also
+    // need the same count.
+    pub fn threads(self, num_thread: usize) -> Self {
+        Self { num_thread, ..self }
+    }
+
+    // Only fixes the order of the data: seeds the loader's shuffling when training starts, unless
+    // it has a seed of its own from `DataLoader::seed`. The weights are drawn when the network is
+    // built, see `NetworkBuilder::seed` to make those reproducible too.
+    pub fn seed(self, seed: u64) -> Self {
+        Self {
+            seed: Some(seed),
+            ..self
+        }
+    }
+
+    // Evaluated after every epoch, its loss and `metrics` end up in the `Report`.
+    pub fn validation(self, dataset: &'a dyn Dataset) -> Self {
+        Self {
+            validation: Some(dataset),
+            ..self
+        }
+    }
+
+    // what to measure on the validation set besides the loss
+    pub fn metrics(self, metrics: &[Metric]) -> Self {
+        Self {
+            metrics: metrics.to_vec(),
+            ..self
+        }
+    }
+
+    pub fn early_stopping(self, early_stopping: EarlyStopping) -> Self {
+        Self {
+            early_stopping: Some(early_stopping),
+            ..self
+        }
+    }
+
+    pub fn checkpoints(self, checkpointer: Checkpointer) -> Self {
+        Self {
+            checkpointer: Some(checkpointer),
+            ..self
+        }
+    }
+
+    // Continues the run `checkpoint` was taken from: `fit` starts from its network, optimizer
+    // state, loader order and early stopping state, and trains the epochs after
+    // `checkpoint.epoch` up to the total it is given. With the same data and settings, the
+    // result is the same as never stopping. The callbacks have to be the ones of the run, in the
+    // same order, to get their state back (see `Callback::save_state`).
+    pub fn resume(self, checkpoint: Checkpoint) -> Self {
+        Self {
+            resume: Some(checkpoint),
+            ..self
+        }
+    }
+
+    // Called in the order they were added. Pass `&mut callback` to look at it after training.
+    pub fn callback(mut self, callback: impl Callback + 'a) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    // Trains for up to `epoch` epochs over the batches of `loader`.
+    pub fn fit<D: Dataset>(
+        &mut self,
+        network: &mut Network,
+        loss_fn: impl Loss + Sync,
+        loader: &mut DataLoader<D>,
+        optimizer: &mut impl Optimizer,
+        epoch: usize,
+    ) -> Result<()> {
+        if self.num_thread == 0 {
+            return Err(Error::InvalidConfig("need at least one thread".into()));
+        }
+        let monitors = [
+            ("early stopping", self.early_stopping.map(|e| e.monitor)),
+            (
+                "checkpoints",
+                self.checkpointer.as_ref().and_then(|c| c.monitor()),
+            ),
+        ];
+        for (what, monitor) in monitors {
+            let Some(monitor) = monitor else {
+                continue;
+            };
+            let missing = match monitor {
+                Monitor::Loss => None,
+                Monitor::ValLoss => self.validation.is_none().then_some("a validation set"),
+                Monitor::Val(metric) => (self.validation.is_none()
+                    || !self.metrics.contains(&metric))
+                .then_some("a validation set with that metric"),
+            };
+            if let Some(missing) = missing {
+                return Err(Error::InvalidConfig(format!(
+                    "{} on {:?} needs {}",
+                    what, monitor, missing
+                )));
+            }
+        }
+        if let (Some(seed), false) = (self.seed, loader.seeded) {
+            loader.rng = ChaCha8Rng::seed_from_u64(seed);
+        }
+        self.train(network, loss_fn, loader, optimizer, epoch)
+    }
 
-impl Trainer {
-    pub fn cpu(
+    // Synchronous data parallel training: every mini-batch is split over one replica of the
+    // network per thread, the replicas' gradients are summed and a single optimizer step is
+    // taken, so the result matches training the same batches on one thread.
+    //
+    // The network is saved to `path` after every epoch, see `SaveNetwork`. Training stops with an
+    // error if the loss becomes NaN or infinite, before the bad gradients reach the parameters.
+    pub fn cpu<D: Dataset>(
         network: &mut Network,
-        loss_fn: impl Loss + std::marker::Send + Copy,
-        train_set: &Vec<Vec<f32>>,
-        train_ans: &Vec<Vec<f32>>,
-        learning_rate: f32,
+        loss_fn: impl Loss + Sync,
+        loader: &mut DataLoader<D>,
+        optimizer: &mut impl Optimizer,
         epoch: usize,
         verbose: bool,
         path: &str,
-    ) {
-        let num_thread = num_cpus::get();
-        let mut pool = Pool::new(num_thread as u32);
-        let batch_size = train_set.len() / num_thread;
-        pool.scoped(|s| {
-            for e in 0..epoch {
-                let (layer_tx, layer_rx) = mpsc::channel();
-                let (loss_tx, loss_rx) = mpsc::channel();
-                for n in 0..num_thread {
-                    let mut net = network.clone();
-                    let layer_tx_clone = layer_tx.clone();
-                    let loss_tx_clone = loss_tx.clone();
-                    s.execute(move || {
-                        let (mut loss, mut output, mut gradient);
-                        loss = 0f32;
-                        for i in n * batch_size..(n + 1) * batch_size {
-                            let x = &train_set[i];
-                            let y = &train_ans[i];
-                            output = net.predict(&x);
-                            loss += loss_fn.loss(&y, &output);
-                            gradient = loss_fn.loss_prime(&y, &output);
-                            for (layer_type, activation_fn) in
-                                net.layers.iter_mut().zip(net.activations.iter_mut()).rev()
-                            {
-                                match activation_fn {
-                                    ActivationFn::Tanh(tanh) => {
-                                        gradient = tanh.b_prop(&gradient);
-                                    }
-                                    ActivationFn::Sigmoid(sigmoid) => {
-                                        gradient = sigmoid.b_prop(&gradient);
-                                    }
-                                    ActivationFn::Relu(relu) => {
-                                        gradient = relu.b_prop(&gradient);
-                                    }
-                                }
-
-                                match layer_type {
-                                    LayerType::Dense(layer) => {
-                                        gradient = layer.b_prop(&gradient, learning_rate);
-                                    }
-                                    LayerType::Conv(layer) => {
-                                        gradient = layer.b_prop(&gradient, learning_rate);
-                                    }
-                                }
-                            }
+    ) -> Result<()> {
+        let mut trainer = Self::new();
+        if verbose {
+            trainer = trainer.callback(Progress::new());
+        }
+        trainer
+            .callback(SaveNetwork::new(path))
+            .fit(network, loss_fn, loader, optimizer, epoch)
+    }
+
+    // Catches what would otherwise panic (or silently train on garbage) in the middle of training.
+    fn check(network: &Network, loss_fn: &impl Loss, batch: &Batch) -> Result<()> {
+        loss_fn.check(batch.targets.shape()[1])?;
+        let Some((input, output)) = network.shapes()? else {
+            return Ok(());
+        };
+        for (expected, found) in [
+            (input.len(), batch.inputs.shape()[1]),
+            (output.len(), batch.targets.shape()[1]),
+        ] {
+            if expected != found {
+                return Err(Error::ShapeMismatch { expected, found });
+            }
+        }
+        Ok(())
+    }
+
+    fn train<D: Dataset>(
+        &mut self,
+        network: &mut Network,
+        loss_fn: impl Loss + Sync,
+        loader: &mut DataLoader<D>,
+        optimizer: &mut impl Optimizer,
+        epoch: usize,
+    ) -> Result<()> {
+        if loader.is_empty() {
+            return Err(Error::InvalidConfig("no training batches".into()));
+        }
+        // the first epoch and step, and the early stopping state: the best monitored value so far
+        // with its weights, and the epochs since
+        let (first, mut step, initial_learning_rate, mut best, mut waited) =
+            match self.resume.take() {
+                Some(checkpoint) => {
+                    *network = checkpoint.network;
+                    optimizer.load_state(&checkpoint.optimizer)?;
+                    loader.rng = checkpoint.loader_rng;
+                    if checkpoint.callbacks.len() != self.callbacks.len() {
+                        return Err(Error::InvalidConfig(format!(
+                            "the checkpoint is of a run with {} callbacks, not {}",
+                            checkpoint.callbacks.len(),
+                            self.callbacks.len()
+                        )));
+                    }
+                    for (callback, state) in self.callbacks.iter_mut().zip(&checkpoint.callbacks) {
+                        if let Some(state) = state {
+                            callback.load_state(state)?;
                         }
-                        layer_tx_clone.send(net.layers).unwrap();
-                        loss_tx_clone.send(loss / train_set.len() as f32).unwrap();
-                    });
+                    }
+                    if let Some(checkpointer) = self.checkpointer.as_mut() {
+                        checkpointer.saved_best = checkpoint.saved_best;
+                    }
+                    (
+                        checkpoint.epoch,
+                        checkpoint.step,
+                        checkpoint.initial_learning_rate,
+                        checkpoint.best,
+                        checkpoint.waited,
+                    )
                 }
-
-                drop(layer_tx);
-                drop(loss_tx);
-
-                for layers in layer_rx {
-                    for (train_layer, layer) in layers.iter().zip(network.layers.iter_mut()) {
-                        match (train_layer, layer) {
-                            (LayerType::Dense(train_dense), LayerType::Dense(dense)) => {
-                                for (train_w, w) in
-                                    train_dense.weights.iter().zip(dense.weights.iter_mut())
-                                {
-                                    for col in 0..w.len() {
-                                        w[col] = (w[col] + train_w[col]) / 2.0;
-                                    }
-                                }
-
-                                for col in 0..dense.biases.len() {
-                                    dense.biases[col] =
-                                        (dense.biases[col] + train_dense.biases[col]) / 2.0;
-                                }
-                            }
-                            (LayerType::Conv(train_conv), LayerType::Conv(conv)) => {
-                                // z_depth. At the time I wrote this 3d conv, I did not have a
-                                // complete picture, I called this depth in its implementation
-                                for (train_z, z) in
-                                    train_conv.kernels.iter().zip(conv.kernels.iter_mut())
-                                {
-                                    for (train_r, r) in train_z.iter().zip(z.iter_mut()) {
-                                        for col in 0..train_r.len() {
-                                            r[col] = (r[col] + train_r[col]) / 2.0;
-                                        }
-                                    }
-                                }
-
-                                //biases
-                                for (train_r, r) in
-                                    train_conv.biases.iter().zip(conv.biases.iter_mut())
-                                {
-                                    for col in 0..train_r.len() {
-                                        r[col] = (r[col] + train_r[col]) / 2.0;
-                                    }
-                                }
+                None => (0, 0, optimizer.learning_rate(), None, 0),
+            };
+        let run = Run {
+            batches: loader.len(),
+            initial_learning_rate,
+        };
+        let callbacks = &mut self.callbacks;
+        // a stop asked for at the end of an epoch, or before it
+        let mut finish = run_hooks(
+            callbacks,
+            network,
+            optimizer,
+            run,
+            (first, 0, step),
+            |c, ctx| c.on_train_begin(ctx),
+        )?;
+        let mut last = first;
+        let num_thread = self.num_thread;
+        let loss_fn = &loss_fn;
+        let mut pool = Pool::new(num_thread as u32);
+        let mut replicas = vec![network.clone(); num_thread];
+        for e in first..epoch {
+            if finish {
+                break;
+            }
+            last = e;
+            finish = run_hooks(
+                callbacks,
+                network,
+                optimizer,
+                run,
+                (e, 0, step),
+                |c, ctx| c.on_epoch_begin(ctx),
+            )?;
+            let mut epoch_loss = 0f32;
+            let mut samples = 0;
+            let mut b = 0;
+            let mut stop = false;
+            loader.for_each_batch(|batch| {
+                Self::check(network, loss_fn, &batch)?;
+                stop |= run_hooks(
+                    callbacks,
+                    network,
+                    optimizer,
+                    run,
+                    (e, b, step),
+                    |c, ctx| c.on_batch_begin(ctx),
+                )?;
+                let size = batch.len();
+                let per_thread = size.div_ceil(num_thread);
+                let mut losses: Vec<Result<f32>> = (0..num_thread).map(|_| Ok(0f32)).collect();
+                let batch = &batch;
+                pool.scoped(|s| {
+                    for (n, (replica, loss)) in
+                        replicas.iter_mut().zip(losses.iter_mut()).enumerate()
+                    {
+                        let from = (n * per_thread).min(size);
+                        let to = (from + per_thread).min(size);
+                        s.execute(move || {
+                            replica.zero_grad();
+                            if from < to {
+                                *loss = Self::backward(replica, loss_fn, batch, from..to);
                             }
-                            _ => (),
-                        }
+                        });
                     }
+                });
+
+                let mut batch_loss = 0f32;
+                for loss in losses {
+                    batch_loss += loss?;
+                }
+                if !batch_loss.is_finite() {
+                    return Err(Error::NaN { epoch: e });
+                }
+
+                // all-reduce: sum the replicas' gradients in a fixed order, step once and hand
+                // the new parameters back to every replica
+                network.zero_grad();
+                for replica in replicas.iter_mut() {
+                    network.accumulate_grads(replica);
                 }
-                let loss: f32 = loss_rx.iter().sum::<f32>() / num_thread as f32;
-                if verbose {
-                    println!("epoch: {} to {}", e + 1, loss)
+                optimizer.step(network.params());
+                step += 1;
+                for replica in replicas.iter_mut() {
+                    network.copy_params_to(replica);
                 }
-                network.save_to_file(&path).unwrap();
+                epoch_loss += batch_loss;
+                samples += size;
+                let mean = batch_loss / size as f32;
+                stop |= run_hooks(
+                    callbacks,
+                    network,
+                    optimizer,
+                    run,
+                    (e, b, step),
+                    |c, ctx| c.on_batch_end(ctx, mean),
+                )?;
+                b += 1;
+                Ok(!stop)
+            })?;
+            // an epoch cut short by a batch hook still gets reported and checkpointed
+            finish |= stop;
+
+            let mut report = Report {
+                epoch: e,
+                loss: epoch_loss / samples as f32,
+                val_loss: None,
+                val_metrics: Vec::new(),
+            };
+            if let Some(dataset) = self.validation {
+                let (loss, scores) = metrics::evaluate_pooled(
+                    network,
+                    loss_fn,
+                    dataset,
+                    &self.metrics,
+                    loader.batch_size(),
+                    &mut pool,
+                )?;
+                report.val_loss = Some(loss);
+                report.val_metrics = self.metrics.iter().copied().zip(scores).collect();
+            }
+            finish |= run_hooks(
+                callbacks,
+                network,
+                optimizer,
+                run,
+                (e, b, step),
+                |c, ctx| c.on_epoch_end(ctx, &report),
+            )?;
+
+            if let Some(stopping) = self.early_stopping {
+                // checked in `fit`
+                let value = report.get(stopping.monitor).unwrap();
+                let improved = match best {
+                    Some((best, _)) => stopping.monitor.improves(value, best, stopping.min_delta),
+                    None => !value.is_nan(),
+                };
+                if improved {
+                    let weights = stopping.restore_best.then(|| network.clone());
+                    best = Some((value, weights));
+                    waited = 0;
+                } else {
+                    waited += 1;
+                    finish |= waited >= stopping.patience;
+                }
+            }
+            if let Some(checkpointer) = self.checkpointer.as_mut() {
+                checkpointer.epoch_end(&report, || {
+                    Ok(Checkpoint {
+                        network: network.clone(),
+                        optimizer: optimizer.save_state()?,
+                        epoch: e + 1,
+                        step,
+                        initial_learning_rate,
+                        loader_rng: loader.rng.clone(),
+                        callbacks: callbacks
+                            .iter()
+                            .map(|c| c.save_state())
+                            .collect::<Result<_>>()?,
+                        best: best.clone(),
+                        waited,
+                        saved_best: None,
+                    })
+                })?;
             }
+        }
+        if let Some((_, Some(weights))) = best {
+            *network = weights;
+        }
+        run_hooks(
+            callbacks,
+            network,
+            optimizer,
+            run,
+            (last, 0, step),
+            |c, ctx| c.on_train_end(ctx),
+        )?;
+        Ok(())
+    }
+
+    // Accumulates the gradients of the `rows` shard of a mini-batch and returns the summed loss
+    // of the shard.
+    fn backward(
+        net: &mut Network,
+        loss_fn: &impl Loss,
+        batch: &Batch,
+        rows: Range<usize>,
+    ) -> Result<f32> {
+        let width = batch.inputs.shape()[1];
+        let xs = &batch.inputs.as_slice()[rows.start * width..rows.end * width];
+        let output = net.forward(&Tensor::new(xs.to_vec(), &[rows.len(), width]))?;
+        let ys = rows.map(|i| batch.targets.row(i));
+
+        // the batch loss is the mean of the sample losses, so the summed gradients of the batch
+        // end up averaged
+        let scale = batch.len() as f32;
+        let mut loss = 0f32;
+        let mut gradient = Vec::with_capacity(output.len());
+        let fused = net.softmax_logits().and_then(|logits| {
+            ys.clone()
+                .enumerate()
+                .map(|(i, y)| loss_fn.softmax_loss(y, logits.row(i)))
+                .collect::<Option<Vec<_>>>()
         });
+        if let Some(fused) = fused {
+            for (l, g) in fused {
+                loss += l;
+                gradient.extend(g.iter().map(|g| g / scale));
+            }
+            net.backward_logits(Tensor::new(gradient, output.shape()))?;
+            return Ok(loss);
+        }
+        for (i, y) in ys.enumerate() {
+            loss += loss_fn.loss(y, output.row(i));
+            gradient.extend(
+                loss_fn
+                    .loss_prime(y, output.row(i))
+                    .iter()
+                    .map(|g| g / scale),
+            );
+        }
+        net.backward(Tensor::new(gradient, output.shape()));
+        Ok(loss)
+    }
+}
+
+// what the `Context` of every hook of a run has in common
+#[derive(Clone, Copy)]
+struct Run {
+    batches: usize,
+    initial_learning_rate: f32,
+}
+
+// Runs `hook` on every callback at (epoch, batch, step), returning whether one of them asked to
+// stop.
+fn run_hooks(
+    callbacks: &mut [Box<dyn Callback + '_>],
+    network: &Network,
+    optimizer: &mut impl Optimizer,
+    run: Run,
+    (epoch, batch, step): (usize, usize, usize),
+    mut hook: impl FnMut(&mut dyn Callback, &mut Context) -> Result<()>,
+) -> Result<bool> {
+    let mut ctx = Context {
+        network,
+        optimizer,
+        epoch,
+        batch,
+        batches: run.batches,
+        step,
+        initial_learning_rate: run.initial_learning_rate,
+        stop: false,
+    };
+    for callback in callbacks.iter_mut() {
+        hook(callback.as_mut(), &mut ctx)?;
+    }
+    Ok(ctx.stop)
+}
+
+#[test]
+fn data_parallel_matches_single_thread() {
+    use crate::activations::{ActivationFn, Tanh};
+    use crate::data::InMemory;
+    use crate::layer::{dense::DenseLayer, LayerType};
+    use crate::loss::MSE;
+    use crate::network::Net;
+    use crate::optim::SGD;
+
+    let network = Network::new(vec![
+        Net::Layer(LayerType::Dense(DenseLayer::new(3, 4))),
+        Net::Activation(ActivationFn::Tanh(Tanh::default())),
+        Net::Layer(LayerType::Dense(DenseLayer::new(4, 2))),
+        Net::Activation(ActivationFn::Tanh(Tanh::default())),
+    ]);
+    let xs: Vec<Vec<f32>> = (0..37)
+        .map(|i| vec![i as f32 / 37f32, (i % 5) as f32 / 5f32, 1f32])
+        .collect();
+    let ys: Vec<Vec<f32>> = (0..37).map(|i| vec![(i % 2) as f32, 0.5]).collect();
+
+    let mut trained = Vec::new();
+    for num_thread in [1, 3] {
+        let mut net = network.clone();
+        let mut optimizer = SGD::momentum(0.1, 0.9);
+        let mut loader = DataLoader::new(InMemory::new(xs.clone(), ys.clone()).unwrap(), 8);
+        Trainer::new()
+            .threads(num_thread)
+            .fit(&mut net, MSE, &mut loader, &mut optimizer, 3)
+            .unwrap();
+        trained.push(net);
+    }
+    let (single, parallel) = trained.split_at_mut(1);
+    for (a, b) in single[0].params().into_iter().zip(parallel[0].params()) {
+        for (a, b) in a.value.as_slice().iter().zip(b.value.as_slice()) {
+            assert!((a - b).abs() < 1e-5);
+        }
     }
 }
+
+#[test]
+fn trainer_reports_errors() {
+    use crate::activations::{ActivationFn, Identity};
+    use crate::data::InMemory;
+    use crate::layer::{dense::DenseLayer, LayerType};
+    use crate::loss::MSE;
+    use crate::network::Net;
+    use crate::optim::SGD;
+
+    let network = Network::new(vec![
+        Net::Layer(LayerType::Dense(DenseLayer::new(2, 1))),
+        Net::Activation(ActivationFn::Identity(Identity::default())),
+    ]);
+    let xs = vec![vec![1f32, 2f32]; 4];
+    let ys = vec![vec![1f32]; 4];
+    let train = |xs: &[Vec<f32>], ys: &[Vec<f32>], lr: f32, batch_size: usize| {
+        let mut net = network.clone();
+        let dataset = InMemory::new(xs.to_vec(), ys.to_vec())?;
+        Trainer::new().threads(2).fit(
+            &mut net,
+            MSE,
+            &mut DataLoader::new(dataset, batch_size),
+            &mut SGD::new(lr),
+            100,
+        )
+    };
+    assert!(train(&xs, &ys, 0.01, 2).is_ok());
+    assert!(matches!(
+        train(&xs, &ys, 0.01, 0),
+        Err(Error::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        train(&xs, &ys[..3], 0.01, 2),
+        Err(Error::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        train(&xs, &vec![vec![1f32, 0f32]; 4], 0.01, 2),
+        Err(Error::ShapeMismatch {
+            expected: 1,
+            found: 2
+        })
+    ));
+    // diverges until the loss overflows
+    assert!(matches!(train(&xs, &ys, 10f32, 2), Err(Error::NaN { .. })));
+    // an infinite loss stops training too, even when the weights never become NaN
+    let huge = vec![vec![1e30f32, -1e30f32]; 4];
+    assert!(matches!(
+        train(&huge, &ys, 0f32, 4),
+        Err(Error::NaN { epoch: 0 })
+    ));
+}
+
+#[test]
+fn early_stopping_restores_best_weights() {
+    use crate::activations::{ActivationFn, Sigmoid};
+    use crate::callbacks::on_epoch_end;
+    use crate::data::InMemory;
+    use crate::layer::{dense::DenseLayer, LayerType};
+    use crate::loss::MSE;
+    use crate::network::Net;
+    use crate::optim::SGD;
+
+    let network = Network::new(vec![
+        Net::Layer(LayerType::Dense(DenseLayer::new(2, 1))),
+        Net::Activation(ActivationFn::Sigmoid(Sigmoid::default())),
+    ]);
+    let xs: Vec<Vec<f32>> = (0..16)
+        .map(|i| vec![(i % 4) as f32, (i / 4) as f32])
+        .collect();
+    let ys: Vec<Vec<f32>> = (0..16).map(|i| vec![(i % 2) as f32]).collect();
+    let train_set = InMemory::new(xs.clone(), ys).unwrap();
+    // the opposite labels, so fitting the training set makes validation worse at some point
+    let val_set = InMemory::new(xs, (0..16).map(|i| vec![((i + 1) % 2) as f32]).collect()).unwrap();
+
+    // a learning rate of 0 never improves: the first epoch and `patience` more
+    let mut epochs = 0;
+    Trainer::new()
+        .threads(1)
+        .early_stopping(EarlyStopping::new(Monitor::Loss, 3))
+        .callback(on_epoch_end(|_, _| {
+            epochs += 1;
+            Ok(())
+        }))
+        .fit(
+            &mut network.clone(),
+            MSE,
+            &mut DataLoader::new(&train_set, 4),
+            &mut SGD::new(0f32),
+            50,
+        )
+        .unwrap();
+    assert_eq!(epochs, 4);
+
+    let mut net = network.clone();
+    let mut seen = Vec::new();
+    Trainer::new()
+        .threads(2)
+        .validation(&val_set)
+        .metrics(&[Metric::Accuracy])
+        .early_stopping(EarlyStopping::new(Monitor::ValLoss, 5))
+        .callback(on_epoch_end(|report, network| {
+            assert_eq!(report.val_metrics.len(), 1);
+            seen.push((report.val_loss.unwrap(), network.clone()));
+            Ok(())
+        }))
+        .fit(
+            &mut net,
+            MSE,
+            &mut DataLoader::new(&train_set, 4),
+            &mut SGD::new(0.5),
+            30,
+        )
+        .unwrap();
+    let (_, best) = seen
+        .iter()
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+        .unwrap();
+    assert_eq!(
+        serde_cbor::to_vec(&net).unwrap(),
+        serde_cbor::to_vec(best).unwrap()
+    );
+
+    let missing = Trainer::new()
+        .early_stopping(EarlyStopping::new(Monitor::Val(Metric::Accuracy), 1))
+        .fit(
+            &mut network.clone(),
+            MSE,
+            &mut DataLoader::new(&train_set, 4),
+            &mut SGD::new(0.1),
+            1,
+        );
+    assert!(matches!(missing, Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn callbacks_see_every_hook_and_can_stop() {
+    use crate::activations::{ActivationFn, Identity};
+    use crate::callbacks::CsvLogger;
+    use crate::checkpoint::Checkpointer;
+    use crate::data::InMemory;
+    use crate::layer::{dense::DenseLayer, LayerType};
+    use crate::loss::MSE;
+    use crate::network::Net;
+    use crate::optim::SGD;
+
+    // records the hooks it sees and stops at batch `stop_at` of epoch 1
+    struct Recorder {
+        hooks: Vec<String>,
+        stop_at: usize,
+    }
+    impl Callback for Recorder {
+        fn on_train_begin(&mut self, _ctx: &mut Context) -> Result<()> {
+            self.hooks.push("train".into());
+            Ok(())
+        }
+        fn on_train_end(&mut self, ctx: &mut Context) -> Result<()> {
+            self.hooks.push(format!("end {}", ctx.epoch));
+            Ok(())
+        }
+        fn on_epoch_begin(&mut self, ctx: &mut Context) -> Result<()> {
+            self.hooks.push(format!("epoch {}", ctx.epoch));
+            Ok(())
+        }
+        fn on_epoch_end(&mut self, ctx: &mut Context, report: &Report) -> Result<()> {
+            assert_eq!(ctx.epoch, report.epoch);
+            self.hooks.push(format!("report {}", report.epoch));
+            Ok(())
+        }
+        fn on_batch_end(&mut self, ctx: &mut Context, loss: f32) -> Result<()> {
+            assert!(loss.is_finite());
+            self.hooks
+                .push(format!("batch {}/{}", ctx.batch, ctx.batches));
+            if ctx.epoch == 1 && ctx.batch == self.stop_at {
+                ctx.stop();
+            }
+            Ok(())
+        }
+    }
+
+    let network = Network::new(vec![
+        Net::Layer(LayerType::Dense(DenseLayer::new(2, 1))),
+        Net::Activation(ActivationFn::Identity(Identity::default())),
+    ]);
+    let dataset = InMemory::new(vec![vec![1f32, 2f32]; 6], vec![vec![1f32]; 6]).unwrap();
+    let dir = std::env::temp_dir().join(format!("callbacks-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log.csv");
+    let mut recorder = Recorder {
+        hooks: Vec::new(),
+        stop_at: 0,
+    };
+    Trainer::new()
+        .validation(&dataset)
+        .metrics(&[Metric::MeanAbsoluteError])
+        .callback(&mut recorder)
+        .callback(CsvLogger::new(&path))
+        .checkpoints(Checkpointer::new(&dir))
+        .fit(
+            &mut network.clone(),
+            MSE,
+            &mut DataLoader::new(&dataset, 4),
+            &mut SGD::new(0.01),
+            5,
+        )
+        .unwrap();
+    // stopped after the first batch of the second epoch, which is still reported and saved
+    assert_eq!(
+        recorder.hooks,
+        vec![
+            "train",
+            "epoch 0",
+            "batch 0/2",
+            "batch 1/2",
+            "report 0",
+            "epoch 1",
+            "batch 0/2",
+            "report 1",
+            "end 1"
+        ]
+    );
+    let csv = std::fs::read_to_string(&path).unwrap();
+    let saved = dir.join("epoch-2.ckpt").exists() && !dir.join("epoch-3.ckpt").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(saved);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "epoch,loss,val_loss,val_mae");
+    assert!(lines[1].starts_with("1,") && lines[2].starts_with("2,"));
+}
@@ -0,0 +1,104 @@
+use crate::builder::Shape;
+use crate::error::{Error, Result};
+use crate::layer::LayerType;
+use crate::network::{Net, Network};
+
+// Everything `Network::predict_into` needs for one sample, sized once from the shapes of the
+// network: two buffers the modules read from and write to in turn, and the im2col matrix of the
+// biggest convolution.
+//
+//     let mut ws = Workspace::new(&network)?;
+//     let mut out = vec![0f32; 10];
+//     for image in images.chunks(784) {
+//         network.predict_into(image, &mut ws, &mut out)?;
+//     }
+pub struct Workspace {
+    pub(crate) shapes: Vec<Shape>,
+    pub(crate) buffers: [Vec<f32>; 2],
+    pub(crate) cols: Vec<f32>,
+}
+
+impl Workspace {
+    pub fn new(network: &Network) -> Result<Self> {
+        let Some(shapes) = network.module_shapes()? else {
+            return Err(Error::InvalidConfig(
+                "a workspace needs a network with at least one layer".into(),
+            ));
+        };
+        let size = shapes.iter().map(Shape::len).max().unwrap_or(0);
+        let cols = network
+            .modules
+            .iter()
+            .map(|module| match module {
+                Net::Layer(LayerType::Conv(layer)) => layer.cols_len(),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0);
+        Ok(Workspace {
+            shapes,
+            buffers: [vec![0f32; size], vec![0f32; size]],
+            cols: vec![0f32; cols],
+        })
+    }
+}
+
+#[test]
+fn predict_into_matches_predict() {
+    use crate::activations::{ActivationFn, PRelu, Softmax, Swish};
+    use crate::layer::convolution::{ConvOptions, Padding};
+    use crate::tensor::Tensor;
+
+    let mut prelu = PRelu::new(2).unwrap();
+    prelu.slopes = Tensor::new(vec![0.1, 0.4], &[2]);
+    let options = ConvOptions {
+        padding: Padding::Same,
+        ..ConvOptions::default()
+    };
+    let network = Network::builder((1, 8, 8))
+        .conv_with((2, 3), options)
+        .activation(ActivationFn::PRelu(prelu))
+        .max_pool(2, 2, 0)
+        .dense(5)
+        .activation(ActivationFn::Swish(Swish::new(1.5)))
+        .dense(3)
+        .activation(ActivationFn::Softmax(Softmax::default()))
+        .build()
+        .unwrap();
+    let mut ws = Workspace::new(&network).unwrap();
+    let mut out = [0f32; 3];
+    for sample in 0..3 {
+        let input: Vec<f32> = (0..64)
+            .map(|i| ((i + sample * 7) as f32 * 0.37).sin())
+            .collect();
+        network.predict_into(&input, &mut ws, &mut out).unwrap();
+        for (a, b) in out.iter().zip(network.predict_ref(&input).unwrap()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    let input = [0.5f32; 64];
+    assert!(matches!(
+        network.predict_into(&input[..10], &mut ws, &mut out),
+        Err(Error::ShapeMismatch {
+            expected: 64,
+            found: 10
+        })
+    ));
+    let other = Network::builder(4).dense(3).build().unwrap();
+    assert!(matches!(
+        other.predict_into(&[0f32; 4], &mut ws, &mut out),
+        Err(Error::InvalidConfig(_))
+    ));
+
+    // same modules, input and output, only the hidden layer differs
+    let hidden = |size| Network::builder(4).dense(size).dense(3).build().unwrap();
+    let mut ws = Workspace::new(&hidden(8)).unwrap();
+    assert!(matches!(
+        hidden(32).predict_into(&[0f32; 4], &mut ws, &mut out),
+        Err(Error::InvalidConfig(_))
+    ));
+    hidden(8)
+        .predict_into(&[0f32; 4], &mut ws, &mut out)
+        .unwrap();
+}
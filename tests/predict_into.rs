@@ -0,0 +1,62 @@
+// In a file of its own: the counting allocator replaces the global one for the whole test binary.
+use neural_network::activations::{ActivationFn, PRelu, Softmax, Swish};
+use neural_network::layer::convolution::{ConvOptions, Padding};
+use neural_network::network::Network;
+use neural_network::tensor::Tensor;
+use neural_network::workspace::Workspace;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// counts the allocations of the current thread, tests run on threads of their own
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|n| n.get())
+}
+
+#[test]
+fn predict_into_does_not_allocate() {
+    let mut prelu = PRelu::new(2).unwrap();
+    prelu.slopes = Tensor::new(vec![0.1, 0.4], &[2]);
+    let options = ConvOptions {
+        padding: Padding::Same,
+        ..ConvOptions::default()
+    };
+    let network = Network::builder((1, 8, 8))
+        .conv_with((2, 3), options)
+        .activation(ActivationFn::PRelu(prelu))
+        .max_pool(2, 2, 0)
+        .dense(5)
+        .activation(ActivationFn::Swish(Swish::new(1.5)))
+        .dense(3)
+        .activation(ActivationFn::Softmax(Softmax::default()))
+        .build()
+        .unwrap();
+    let mut ws = Workspace::new(&network).unwrap();
+    let mut out = [0f32; 3];
+    let input = [0.5f32; 64];
+    network.predict_into(&input, &mut ws, &mut out).unwrap();
+
+    let before = allocations();
+    network.predict_into(&input, &mut ws, &mut out).unwrap();
+    network.shapes().unwrap();
+    assert_eq!(allocations(), before);
+}
@@ -24,12 +24,12 @@ impl WasmNN {
         wasmNN.0 = Network::default();
         let response = reqwest::get(url).await?.bytes().await?;
         let model: Vec<u8> = response.into();
-        wasmNN.0.from_slice(&model).unwrap();
+        wasmNN.0.from_slice(&model)?;
         Ok(wasmNN)
     }
 
-    pub fn predict(&self, input: Vec<f32>) -> Vec<f32> {
-        self.0.predict_ref(&input)
+    pub fn predict(&self, input: Vec<f32>) -> Result<Vec<f32>, JsError> {
+        Ok(self.0.predict_ref(&input)?)
     }
 }
 